@@ -19,6 +19,7 @@ fn main() {
         },
         stakes: Default::default(),
         init_fee_pool: CoinValue(0),
+        params: None,
     }
     .realize(&Database::new(meshacas))
     .seal(None)
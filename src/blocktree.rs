@@ -0,0 +1,327 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use novasmt::ContentAddrStore;
+use themelio_structs::{Block, ConsensusProof};
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+use crate::{ConsensusProofError, SealedState, StateError};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// An error that happens while adding to a block tree
+pub enum BlockTreeError {
+    #[error("unknown parent block {0}")]
+    UnknownParent(HashVal),
+    #[error("unknown block {0}")]
+    UnknownBlock(HashVal),
+    #[error(transparent)]
+    InvalidBlock(#[from] StateError),
+    #[error(transparent)]
+    InvalidProof(#[from] ConsensusProofError),
+}
+
+/// A rule that picks the canonical chain of a [BlockTree] among its competing tips.
+pub trait ForkChoiceRule<C: ContentAddrStore> {
+    /// Compares two tips of the tree. The greatest tip is canonical.
+    fn compare(&self, tree: &BlockTree<C>, a: HashVal, b: HashVal) -> Ordering;
+}
+
+/// Prefers the highest tip.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LongestChain;
+
+impl<C: ContentAddrStore> ForkChoiceRule<C> for LongestChain {
+    fn compare(&self, tree: &BlockTree<C>, a: HashVal, b: HashVal) -> Ordering {
+        tree.nodes[&a]
+            .state
+            .inner_ref()
+            .height
+            .cmp(&tree.nodes[&b].state.inner_ref().height)
+    }
+}
+
+/// Prefers the tip whose chain has the most stake in consensus proofs, then the highest tip.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StakeWeighted;
+
+impl<C: ContentAddrStore> ForkChoiceRule<C> for StakeWeighted {
+    fn compare(&self, tree: &BlockTree<C>, a: HashVal, b: HashVal) -> Ordering {
+        tree.nodes[&a]
+            .chain_votes
+            .cmp(&tree.nodes[&b].chain_votes)
+            .then_with(|| LongestChain.compare(tree, a, b))
+    }
+}
+
+struct Node<C: ContentAddrStore> {
+    state: SealedState<C>,
+    parent: Option<HashVal>,
+    children: BTreeSet<HashVal>,
+    cproof: Option<ConsensusProof>,
+    votes: u128,
+    /// The votes of this block and its ancestors, including finalized ones.
+    chain_votes: u128,
+}
+
+/// A tree of competing blocks, rooted at the latest finalized block. The canonical chain is picked by a [ForkChoiceRule], [LongestChain] by default.
+pub struct BlockTree<C: ContentAddrStore> {
+    nodes: BTreeMap<HashVal, Node<C>>,
+    root: HashVal,
+    fork_choice: Box<dyn ForkChoiceRule<C> + Send + Sync>,
+}
+
+impl<C: ContentAddrStore> BlockTree<C> {
+    /// Creates a block tree rooted at the given state.
+    pub fn new(root: SealedState<C>) -> Self {
+        let root_hash = root.header().hash();
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            root_hash,
+            Node {
+                state: root,
+                parent: None,
+                children: BTreeSet::new(),
+                cproof: None,
+                votes: 0,
+                chain_votes: 0,
+            },
+        );
+        Self {
+            nodes,
+            root: root_hash,
+            fork_choice: Box::new(LongestChain),
+        }
+    }
+
+    /// Sets the fork choice rule.
+    pub fn fork_choice(mut self, rule: impl ForkChoiceRule<C> + Send + Sync + 'static) -> Self {
+        self.fork_choice = Box::new(rule);
+        self
+    }
+
+    /// Returns the header hash of the root, which is the latest finalized block.
+    pub fn root(&self) -> HashVal {
+        self.root
+    }
+
+    /// Returns the number of blocks in the tree, including the root.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns whether the tree only contains its root.
+    pub fn has_no_descendants(&self) -> bool {
+        self.nodes.len() == 1
+    }
+
+    /// Gets the state after the block with the given header hash.
+    pub fn get(&self, hash: HashVal) -> Option<&SealedState<C>> {
+        self.nodes.get(&hash).map(|node| &node.state)
+    }
+
+    /// Gets the consensus proof of the block with the given header hash, if one was added.
+    pub fn cproof(&self, hash: HashVal) -> Option<&ConsensusProof> {
+        self.nodes.get(&hash)?.cproof.as_ref()
+    }
+
+    /// Applies a block on top of its parent in the tree, returning the block's header hash.
+    pub fn apply_block(&mut self, block: &Block) -> Result<HashVal, BlockTreeError> {
+        let hash = block.header.hash();
+        if self.nodes.contains_key(&hash) {
+            return Ok(hash);
+        }
+        let parent = self
+            .nodes
+            .get_mut(&block.header.previous)
+            .ok_or(BlockTreeError::UnknownParent(block.header.previous))?;
+        let state = parent.state.apply_block(block)?;
+        parent.children.insert(hash);
+        let chain_votes = parent.chain_votes;
+        self.nodes.insert(
+            hash,
+            Node {
+                state,
+                parent: Some(block.header.previous),
+                children: BTreeSet::new(),
+                cproof: None,
+                votes: 0,
+                chain_votes,
+            },
+        );
+        Ok(hash)
+    }
+
+    /// Adds a consensus proof for a block in the tree, after checking it. Returns the number of syms that signed.
+    pub fn add_cproof(
+        &mut self,
+        hash: HashVal,
+        cproof: ConsensusProof,
+    ) -> Result<u128, BlockTreeError> {
+        let node = self
+            .nodes
+            .get_mut(&hash)
+            .ok_or(BlockTreeError::UnknownBlock(hash))?;
+        let header = node.state.header();
+        let votes =
+            node.state
+                .inner_ref()
+                .stakes
+                .verify_cproof(header.height.epoch(), hash, &cproof)?;
+        node.cproof = Some(cproof);
+        node.votes = votes;
+
+        // update the cached chain votes of the block and its descendants
+        let mut stack = vec![hash];
+        while let Some(h) = stack.pop() {
+            let parent_votes = self.nodes[&h]
+                .parent
+                .map_or(0, |parent| self.nodes[&parent].chain_votes);
+            let node = self.nodes.get_mut(&h).unwrap();
+            node.chain_votes = parent_votes.saturating_add(node.votes);
+            stack.extend(node.children.iter().copied());
+        }
+        Ok(votes)
+    }
+
+    /// Returns the header hashes of the tips: the blocks without children.
+    pub fn tips(&self) -> Vec<HashVal> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.children.is_empty())
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    /// Returns the header hash of the tip of the canonical chain.
+    pub fn canonical_tip(&self) -> HashVal {
+        // the tree always has at least one tip, but fall back to the root rather than panic
+        self.tips()
+            .into_iter()
+            .max_by(|a, b| {
+                self.fork_choice
+                    .compare(self, *a, *b)
+                    .then_with(|| b.cmp(a))
+            })
+            .unwrap_or(self.root)
+    }
+
+    /// Returns the header hashes of the canonical chain, from the root to the canonical tip.
+    pub fn canonical_chain(&self) -> Vec<HashVal> {
+        let mut chain: Vec<HashVal> = self.ancestors(self.canonical_tip()).collect();
+        chain.reverse();
+        chain
+    }
+
+    /// Iterates over the given block and its ancestors in the tree, down to the root.
+    pub fn ancestors(&self, hash: HashVal) -> impl Iterator<Item = HashVal> + '_ {
+        std::iter::successors(self.nodes.contains_key(&hash).then_some(hash), move |h| {
+            self.nodes[h].parent
+        })
+    }
+
+    /// Finalizes the given block, making it the new root, and prunes every block that does not descend from it. Returns the pruned blocks.
+    pub fn finalize(&mut self, hash: HashVal) -> Result<Vec<HashVal>, BlockTreeError> {
+        if !self.nodes.contains_key(&hash) {
+            return Err(BlockTreeError::UnknownBlock(hash));
+        }
+        let mut keep = BTreeSet::new();
+        let mut stack = vec![hash];
+        while let Some(h) = stack.pop() {
+            stack.extend(self.nodes[&h].children.iter().copied());
+            keep.insert(h);
+        }
+        let pruned: Vec<HashVal> = self
+            .nodes
+            .keys()
+            .filter(|h| !keep.contains(*h))
+            .copied()
+            .collect();
+        for h in pruned.iter() {
+            self.nodes.remove(h);
+        }
+        self.nodes.get_mut(&hash).unwrap().parent = None;
+        self.root = hash;
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{Block, CoinValue};
+    use tmelcrypt::HashVal;
+
+    use crate::{
+        testing::functions::{create_state, faucet_tx},
+        BlockTree, BlockTreeError, SealedState, StakeWeighted,
+    };
+
+    fn child_block<C: novasmt::ContentAddrStore>(parent: &SealedState<C>, i: u8) -> Block {
+        let mut next = parent.next_state();
+        next.apply_tx(&faucet_tx(i)).unwrap();
+        next.seal(None).to_block()
+    }
+
+    #[test]
+    fn competing_branches() {
+        let stakers: HashMap<_, _> = (0..3)
+            .map(|_| (tmelcrypt::ed25519_keygen().1, CoinValue(100)))
+            .collect();
+        let genesis = create_state(&stakers, 0).seal(None);
+        let mut tree = BlockTree::new(genesis.clone());
+
+        // branch a is two blocks long, branch b only one
+        let a1 = tree.apply_block(&child_block(&genesis, 1)).unwrap();
+        let a2 = tree
+            .apply_block(&child_block(tree.get(a1).unwrap(), 2))
+            .unwrap();
+        let b1 = tree.apply_block(&child_block(&genesis, 3)).unwrap();
+        assert_eq!(tree.len(), 4);
+        assert!(!tree.has_no_descendants());
+        assert_eq!(tree.tips().len(), 2);
+        assert_eq!(tree.canonical_tip(), a2);
+        assert_eq!(tree.canonical_chain(), vec![tree.root(), a1, a2]);
+
+        // blocks need a known parent
+        let orphan = child_block(tree.get(a2).unwrap(), 4);
+        let mut orphan_tree = BlockTree::new(genesis.clone());
+        assert_eq!(
+            orphan_tree.apply_block(&orphan),
+            Err(BlockTreeError::UnknownParent(a2))
+        );
+
+        // with stake-weighted fork choice, a confirmed block wins over a longer branch
+        let mut tree = tree.fork_choice(StakeWeighted);
+        assert_eq!(tree.canonical_tip(), a2);
+        let cproof = stakers
+            .keys()
+            .map(|sk| (sk.to_public(), sk.sign(&b1.0).into()))
+            .collect();
+        assert_eq!(tree.add_cproof(b1, cproof).unwrap(), 300);
+        assert!(tree.add_cproof(a1, Default::default()).is_err());
+        assert_eq!(tree.canonical_tip(), b1);
+        // the stake carries over to the block's descendants
+        let b2 = tree
+            .apply_block(&child_block(tree.get(b1).unwrap(), 5))
+            .unwrap();
+        assert_eq!(tree.canonical_tip(), b2);
+
+        // finalizing b1 prunes branch a
+        let mut pruned = tree.finalize(b1).unwrap();
+        pruned.sort();
+        let mut expected = vec![genesis.header().hash(), a1, a2];
+        expected.sort();
+        assert_eq!(pruned, expected);
+        assert_eq!(tree.root(), b1);
+        assert_eq!(tree.canonical_chain(), vec![b1, b2]);
+        assert_eq!(
+            tree.finalize(HashVal([1; 32])),
+            Err(BlockTreeError::UnknownBlock(HashVal([1; 32])))
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
+};
+
+use novasmt::ContentAddrStore;
+use themelio_structs::{Address, ProposerAction, Transaction, TxHash};
+
+use crate::{
+    mempool::{compare_fee_rates, tip_and_weight},
+    SealedState, StateError,
+};
+
+/// A builder for block templates, which picks the candidate transactions that maximize tips within the block size limits.
+///
+/// Transactions are picked greedily by the tip per weight of their "package": the transaction plus the unpicked candidates it depends on.
+pub struct BlockBuilder<C: ContentAddrStore> {
+    parent: SealedState<C>,
+    candidates: BTreeMap<TxHash, Transaction>,
+    fee_multiplier_delta: Option<i8>,
+    reward_dest: Address,
+}
+
+impl<C: ContentAddrStore> BlockBuilder<C> {
+    /// Creates a builder for the block after `parent`, sending the proposer reward to `reward_dest`.
+    pub fn new(parent: SealedState<C>, reward_dest: Address) -> Self {
+        Self {
+            parent,
+            candidates: BTreeMap::new(),
+            fee_multiplier_delta: None,
+            reward_dest,
+        }
+    }
+
+    /// Adds a candidate transaction.
+    pub fn transaction(mut self, tx: Transaction) -> Self {
+        self.candidates.insert(tx.hash_nosigs(), tx);
+        self
+    }
+
+    /// Adds many candidate transactions.
+    pub fn transactions(mut self, txx: impl IntoIterator<Item = Transaction>) -> Self {
+        self.candidates
+            .extend(txx.into_iter().map(|tx| (tx.hash_nosigs(), tx)));
+        self
+    }
+
+    /// Sets the fee multiplier vote. By default, it goes from -127 for an empty block to 127 for a full one.
+    pub fn fee_multiplier_delta(mut self, delta: i8) -> Self {
+        self.fee_multiplier_delta = Some(delta);
+        self
+    }
+
+    /// Selects the transactions and seals the block, also returning the rejected candidates.
+    pub fn build(self) -> (SealedState<C>, Vec<(TxHash, StateError)>) {
+        let mut state = self.parent.next_state();
+        let params = state.params;
+        let fee_multiplier = state.fee_multiplier;
+        let tips_weights: BTreeMap<TxHash, (u128, u128)> = self
+            .candidates
+            .iter()
+            .map(|(h, tx)| (*h, tip_and_weight(tx, fee_multiplier)))
+            .collect();
+
+        let mut children: BTreeMap<TxHash, BTreeSet<TxHash>> = BTreeMap::new();
+        for (h, tx) in self.candidates.iter() {
+            for input in tx.inputs.iter() {
+                if self.candidates.contains_key(&input.txhash) {
+                    children.entry(input.txhash).or_default().insert(*h);
+                }
+            }
+        }
+
+        let mut done: BTreeSet<TxHash> = BTreeSet::new();
+        let mut packages: BTreeMap<TxHash, ScoredPackage> = BTreeMap::new();
+        let mut queue: BinaryHeap<ScoredPackage> = BinaryHeap::new();
+        for h in self.candidates.keys() {
+            let package = self.score_package(*h, &done, &tips_weights);
+            packages.insert(*h, package.clone());
+            queue.push(package);
+        }
+
+        let mut rejected = vec![];
+        let mut total_weight = 0u128;
+        let mut total_txx = 0u64;
+        // take the best package, skipping the ones that are stale or that no longer fit
+        while let Some(best) = queue.pop() {
+            if done.contains(&best.head) || packages.get(&best.head) != Some(&best) {
+                continue;
+            }
+            let ScoredPackage {
+                head,
+                package,
+                weight,
+                ..
+            } = packages.remove(&best.head).unwrap();
+            done.insert(head);
+            let newly_done = if total_weight.saturating_add(weight) > params.max_block_weight
+                || total_txx + package.len() as u64 > params.max_block_txx
+            {
+                log::debug!("skipping {}, which does not fit in the block", head);
+                vec![head]
+            } else {
+                let txx: Vec<Transaction> =
+                    package.iter().map(|h| self.candidates[h].clone()).collect();
+                match state.apply_tx_batch(&txx) {
+                    Ok(()) => {
+                        total_weight += weight;
+                        total_txx += package.len() as u64;
+                        done.extend(package.iter().copied());
+                        package
+                    }
+                    Err(err) => {
+                        log::debug!("rejecting {}: {:?}", head, err);
+                        rejected.push((head, err));
+                        vec![head]
+                    }
+                }
+            };
+
+            // only the packages of the descendants of what became done have changed
+            let mut stack = newly_done;
+            let mut visited = BTreeSet::new();
+            while let Some(h) = stack.pop() {
+                for child in children.get(&h).into_iter().flatten() {
+                    if !done.contains(child) && visited.insert(*child) {
+                        let package = self.score_package(*child, &done, &tips_weights);
+                        packages.insert(*child, package.clone());
+                        queue.push(package);
+                        stack.push(*child);
+                    }
+                }
+            }
+        }
+
+        let fee_multiplier_delta = self.fee_multiplier_delta.unwrap_or_else(|| {
+            let weight_fill = total_weight.saturating_mul(254) / params.max_block_weight.max(1);
+            let txx_fill = total_txx.saturating_mul(254) / params.max_block_txx.max(1);
+            (weight_fill.max(txx_fill as u128).min(254) as i16 - 127) as i8
+        });
+        let sealed = state.seal(Some(ProposerAction {
+            fee_multiplier_delta,
+            reward_dest: self.reward_dest,
+        }));
+        (sealed, rejected)
+    }
+
+    /// Computes the package of the given candidate, along with its total tip and weight.
+    fn score_package(
+        &self,
+        head: TxHash,
+        done: &BTreeSet<TxHash>,
+        tips_weights: &BTreeMap<TxHash, (u128, u128)>,
+    ) -> ScoredPackage {
+        let package = self.package(head, done);
+        let (tip, weight) = package.iter().fold((0u128, 0u128), |(t, w), h| {
+            let (tip, weight) = tips_weights[h];
+            (t.saturating_add(tip), w.saturating_add(weight))
+        });
+        ScoredPackage {
+            head,
+            package,
+            tip,
+            weight,
+        }
+    }
+
+    /// Returns the given candidate, preceded by the candidates it depends on that aren't done yet.
+    fn package(&self, head: TxHash, done: &BTreeSet<TxHash>) -> Vec<TxHash> {
+        fn visit(
+            this: &BTreeMap<TxHash, Transaction>,
+            h: TxHash,
+            done: &BTreeSet<TxHash>,
+            seen: &mut BTreeSet<TxHash>,
+            accum: &mut Vec<TxHash>,
+        ) {
+            if !seen.insert(h) {
+                return;
+            }
+            for input in this[&h].inputs.iter() {
+                if this.contains_key(&input.txhash) && !done.contains(&input.txhash) {
+                    visit(this, input.txhash, done, seen, accum);
+                }
+            }
+            accum.push(h);
+        }
+        let mut accum = vec![];
+        visit(
+            &self.candidates,
+            head,
+            done,
+            &mut BTreeSet::new(),
+            &mut accum,
+        );
+        accum
+    }
+}
+
+/// A candidate's package, ordered by tip per weight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ScoredPackage {
+    head: TxHash,
+    package: Vec<TxHash>,
+    tip: u128,
+    weight: u128,
+}
+
+impl Ord for ScoredPackage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rates((self.tip, self.weight), (other.tip, other.weight))
+            .then_with(|| self.head.cmp(&other.head))
+    }
+}
+
+impl PartialOrd for ScoredPackage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tap::Tap;
+    use themelio_structs::CoinValue;
+
+    use crate::{
+        melvm::Covenant,
+        testing::functions::{create_state, faucet_tx, valid_txx},
+        BlockBuilder, SealedState,
+    };
+
+    #[test]
+    fn picks_highest_tips() {
+        let parent = create_state(&HashMap::new(), 0).seal(None);
+        let candidates: Vec<_> = (0..10u8)
+            .map(|i| faucet_tx(i).tap_mut(|tx| tx.fee = CoinValue(1_000_000 * (i as u128 + 1))))
+            .collect();
+        let mut parent_state = parent.inner_ref().clone();
+        parent_state.params.max_block_txx = 3;
+        let parent = SealedState::from_parts(parent_state, None);
+        let (sealed, rejected) = BlockBuilder::new(parent.clone(), Covenant::always_true().hash())
+            .transactions(candidates.clone())
+            .fee_multiplier_delta(10)
+            .build();
+        assert!(rejected.is_empty());
+        let included = &sealed.inner_ref().transactions;
+        assert_eq!(included.len(), 3);
+        for tx in candidates[7..].iter() {
+            assert!(included.contains_key(&tx.hash_nosigs()));
+        }
+        assert_eq!(sealed.proposer_action().unwrap().fee_multiplier_delta, 10);
+        // a full block votes to raise the fee multiplier by default
+        let (full, _) = BlockBuilder::new(parent.clone(), Covenant::always_true().hash())
+            .transactions(candidates.clone())
+            .build();
+        assert_eq!(full.proposer_action().unwrap().fee_multiplier_delta, 127);
+        assert_eq!(
+            parent.apply_block(&sealed.to_block()).unwrap().header(),
+            sealed.header()
+        );
+    }
+
+    #[test]
+    fn pulls_in_parents_and_rejects_invalid() {
+        let parent = create_state(&HashMap::new(), 0).seal(None);
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        let bad = txx[3].clone().tap_mut(|tx| tx.fee += CoinValue(1));
+        let (sealed, rejected) = BlockBuilder::new(parent, Covenant::always_true().hash())
+            .transactions(txx[..10].iter().rev().cloned())
+            .transaction(bad.clone())
+            .build();
+        let included = &sealed.inner_ref().transactions;
+        assert_eq!(included.len(), 10);
+        for tx in txx[..10].iter() {
+            assert!(included.contains_key(&tx.hash_nosigs()));
+        }
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, bad.hash_nosigs());
+        // a mostly empty block votes to lower it
+        assert!(sealed.proposer_action().unwrap().fee_multiplier_delta < 0);
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use novasmt::ContentAddrStore;
+use serde::{Deserialize, Serialize};
+use themelio_structs::{Block, BlockHeight, CoinID, Header, Transaction, TxHash};
+
+use crate::{
+    state::check_block_size, verify_transaction_proof_with_params, witness::replay_witness,
+    BlockWitness, ConsensusParams, SealedState, State, TransactionProof,
+};
+
+/// A compact proof that a block is invalid, checkable against the header of the block's parent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FraudProof {
+    /// The header of the invalid block, with the roots that it claims.
+    pub header: Header,
+    /// The evidence that the header is invalid.
+    pub fraud: Fraud,
+}
+
+/// The evidence in a [FraudProof].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fraud {
+    /// Two transactions in the block spend the same coin.
+    DoubleSpend {
+        coin_id: CoinID,
+        first: Transaction,
+        first_proof: TransactionProof,
+        second: Transaction,
+        second_proof: TransactionProof,
+    },
+    /// A transaction in the block cannot be applied, even though all of its inputs exist.
+    ///
+    /// `ancestors` are the transactions in the block that it spends from, in spending order.
+    InvalidTransaction {
+        tx: Transaction,
+        proof: TransactionProof,
+        ancestors: Vec<(Transaction, TransactionProof)>,
+        witness: BlockWitness,
+    },
+    /// The block is too large, its transactions conflict, or they do not lead to the roots the header claims.
+    ///
+    /// Roots that depend on the proposer action, such as the coins root, cannot be disputed.
+    InvalidBlock {
+        transactions: Vec<Transaction>,
+        witness: BlockWitness,
+    },
+}
+
+impl<C: ContentAddrStore> SealedState<C> {
+    /// Generates a proof that the given block, which builds on this state, is invalid.
+    ///
+    /// Returns `None` if the block is valid, does not build on this state, or is invalid in a way that [Fraud] cannot dispute.
+    pub fn fraud_proof(&self, block: &Block) -> Option<FraudProof> {
+        if block.header.previous != self.header().hash() || self.apply_block(block).is_ok() {
+            return None;
+        }
+        let txx: BTreeMap<TxHash, Transaction> = block
+            .transactions
+            .iter()
+            .map(|tx| (tx.hash_nosigs(), tx.clone()))
+            .collect();
+        let mut committed = self.next_state();
+        committed.transactions = txx.clone();
+        let committed = SealedState::from_parts(committed, None);
+        if committed.inner_ref().transactions_root_hash() != block.header.transactions_hash {
+            return None;
+        }
+        let with_proof = |tx: &Transaction| {
+            let proof = committed.transaction_proof(tx.hash_nosigs()).unwrap();
+            (tx.clone(), proof)
+        };
+        let proven = |fraud| {
+            Some(FraudProof {
+                header: block.header,
+                fraud,
+            })
+        };
+
+        let mut spenders: BTreeMap<CoinID, &Transaction> = BTreeMap::new();
+        for tx in txx.values() {
+            for coin_id in tx.inputs.iter() {
+                match spenders.insert(*coin_id, tx) {
+                    Some(first) if first.hash_nosigs() != tx.hash_nosigs() => {
+                        let (first, first_proof) = with_proof(first);
+                        let (second, second_proof) = with_proof(tx);
+                        return proven(Fraud::DoubleSpend {
+                            coin_id: *coin_id,
+                            first,
+                            first_proof,
+                            second,
+                            second_proof,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for tx in txx.values() {
+            let mut ancestors: Vec<Transaction> =
+                in_spending_order(&txx, [tx]).into_iter().cloned().collect();
+            ancestors.pop();
+            let (invalid, witness) =
+                self.record_witness(|basis| replay_transaction(basis, &ancestors, tx));
+            if invalid {
+                let (tx, proof) = with_proof(tx);
+                return proven(Fraud::InvalidTransaction {
+                    tx,
+                    proof,
+                    ancestors: ancestors.iter().map(with_proof).collect(),
+                    witness,
+                });
+            }
+        }
+
+        let transactions: Vec<Transaction> = in_spending_order(&txx, txx.values())
+            .into_iter()
+            .cloned()
+            .collect();
+        let (invalid, witness) =
+            self.record_witness(|basis| replay_block(basis, &block.header, &transactions));
+        if invalid {
+            return proven(Fraud::InvalidBlock {
+                transactions,
+                witness,
+            });
+        }
+        None
+    }
+}
+
+/// Verifies a fraud proof against the header of the disputed block's parent.
+pub fn verify_fraud_proof(parent: &Header, params: ConsensusParams, proof: &FraudProof) -> bool {
+    let header = &proof.header;
+    if header.previous != parent.hash()
+        || header.network != parent.network
+        || header.height != parent.height + BlockHeight(1)
+    {
+        return false;
+    }
+    let included =
+        |tx: &Transaction, proof| verify_transaction_proof_with_params(&params, header, tx, proof);
+    match &proof.fraud {
+        Fraud::DoubleSpend {
+            coin_id,
+            first,
+            first_proof,
+            second,
+            second_proof,
+        } => {
+            first.hash_nosigs() != second.hash_nosigs()
+                && first.inputs.contains(coin_id)
+                && second.inputs.contains(coin_id)
+                && included(first, first_proof)
+                && included(second, second_proof)
+        }
+        Fraud::InvalidTransaction {
+            tx,
+            proof,
+            ancestors,
+            witness,
+        } => {
+            if !included(tx, proof) || !ancestors.iter().all(|(tx, proof)| included(tx, proof)) {
+                return false;
+            }
+            let ancestors: Vec<Transaction> = ancestors.iter().map(|(tx, _)| tx.clone()).collect();
+            replay_witness(parent, params, witness, |basis| {
+                replay_transaction(basis, &ancestors, tx)
+            }) == Ok(true)
+        }
+        Fraud::InvalidBlock {
+            transactions,
+            witness,
+        } => {
+            replay_witness(parent, params, witness, |basis| {
+                replay_block(basis, header, transactions)
+            }) == Ok(true)
+        }
+    }
+}
+
+/// Returns the given transactions and the ones they spend from, in spending order.
+fn in_spending_order<'a>(
+    txx: &'a BTreeMap<TxHash, Transaction>,
+    roots: impl IntoIterator<Item = &'a Transaction>,
+) -> Vec<&'a Transaction> {
+    fn visit<'a>(
+        txx: &'a BTreeMap<TxHash, Transaction>,
+        tx: &'a Transaction,
+        seen: &mut BTreeSet<TxHash>,
+        accum: &mut Vec<&'a Transaction>,
+    ) {
+        if !seen.insert(tx.hash_nosigs()) {
+            return;
+        }
+        for coin_id in tx.inputs.iter() {
+            if let Some(parent) = txx.get(&coin_id.txhash) {
+                visit(txx, parent, seen, accum);
+            }
+        }
+        accum.push(tx);
+    }
+    let mut seen = BTreeSet::new();
+    let mut accum = vec![];
+    for tx in roots {
+        visit(txx, tx, &mut seen, &mut accum);
+    }
+    accum
+}
+
+/// Returns true iff the ancestors apply, the transaction's inputs then exist, and the transaction does not apply.
+fn replay_transaction<C: ContentAddrStore>(
+    mut basis: State<C>,
+    ancestors: &[Transaction],
+    tx: &Transaction,
+) -> bool {
+    if ancestors
+        .iter()
+        .any(|ancestor| basis.apply_tx(ancestor).is_err())
+    {
+        return false;
+    }
+    if tx
+        .inputs
+        .iter()
+        .any(|coin_id| basis.coins.get_coin(*coin_id).is_none())
+    {
+        return false;
+    }
+    basis.apply_tx(tx).is_err()
+}
+
+/// Returns true iff the header commits to exactly these transactions, and they do not apply or do not lead to the claimed roots.
+fn replay_block<C: ContentAddrStore>(
+    mut basis: State<C>,
+    claimed: &Header,
+    transactions: &[Transaction],
+) -> bool {
+    basis.transactions = transactions
+        .iter()
+        .map(|tx| (tx.hash_nosigs(), tx.clone()))
+        .collect();
+    if basis.transactions.len() != transactions.len()
+        || basis.transactions_root_hash() != claimed.transactions_hash
+    {
+        return false;
+    }
+    basis.transactions.clear();
+    let block = Block {
+        header: *claimed,
+        transactions: transactions.iter().cloned().collect(),
+        proposer_action: None,
+    };
+    if check_block_size(&basis.params, basis.height, &block).is_err()
+        || basis.apply_tx_batch(transactions).is_err()
+    {
+        return true;
+    }
+    let actual = basis.seal(None).header();
+    actual.history_hash != claimed.history_hash
+        || actual.pools_hash != claimed.pools_hash
+        || actual.stakes_hash != claimed.stakes_hash
+        || actual.dosc_speed != claimed.dosc_speed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::CoinValue;
+    use tmelcrypt::HashVal;
+
+    use crate::{
+        testing::functions::{create_state, faucet_tx, valid_txx},
+        verify_fraud_proof, Fraud,
+    };
+
+    #[test]
+    fn fraud_proofs() {
+        let parent = create_state(&HashMap::new(), 0).seal(None);
+        let params = parent.inner_ref().params;
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+
+        // a valid block has no fraud proof
+        let mut next = parent.next_state();
+        next.apply_tx_batch(&[txx[0].clone(), faucet_tx(0)])
+            .unwrap();
+        let good = next.seal(None).to_block();
+        assert!(parent.fraud_proof(&good).is_none());
+
+        // a transaction that creates more than it spends
+        let mut overspend = txx[0].clone();
+        overspend.outputs[0].value += CoinValue(1);
+        let mut next = parent.next_state();
+        next.apply_tx(&faucet_tx(1)).unwrap();
+        next.transactions
+            .insert(overspend.hash_nosigs(), overspend.clone());
+        let bad = next.seal(None).to_block();
+        let proof = parent.fraud_proof(&bad).unwrap();
+        match &proof.fraud {
+            Fraud::InvalidTransaction { tx, ancestors, .. } => {
+                assert_eq!(tx, &overspend);
+                assert!(ancestors.is_empty());
+            }
+            fraud => panic!("unexpected fraud {:?}", fraud),
+        }
+        assert!(verify_fraud_proof(&parent.header(), params, &proof));
+        assert!(!verify_fraud_proof(&bad.header, params, &proof));
+
+        // two transactions spending the same coin
+        let mut other = txx[0].clone();
+        other.fee += CoinValue(1);
+        let mut next = parent.next_state();
+        next.apply_tx(&txx[0]).unwrap();
+        next.transactions.insert(other.hash_nosigs(), other);
+        let bad = next.seal(None).to_block();
+        let proof = parent.fraud_proof(&bad).unwrap();
+        assert!(matches!(proof.fraud, Fraud::DoubleSpend { .. }));
+        assert!(verify_fraud_proof(&parent.header(), params, &proof));
+
+        // a header claiming the wrong stakes root
+        let mut bad = good.clone();
+        bad.header.stakes_hash = HashVal([1; 32]);
+        let proof = parent.fraud_proof(&bad).unwrap();
+        assert!(matches!(proof.fraud, Fraud::InvalidBlock { .. }));
+        assert!(verify_fraud_proof(&parent.header(), params, &proof));
+
+        // the same evidence does not convict the valid header
+        let mut forged = proof;
+        forged.header = good.header;
+        assert!(!verify_fraud_proof(&parent.header(), params, &forged));
+    }
+}
@@ -8,7 +8,7 @@ use themelio_structs::{
 };
 use tmelcrypt::{Ed25519PK, HashVal};
 
-use crate::{melvm::Covenant, CoinMapping, SmtMapping, State};
+use crate::{melvm::Covenant, CoinMapping, ConsensusParams, SmtMapping, State};
 
 /// Configuration of a genesis state. Serializable via serde.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,9 +22,12 @@ pub struct GenesisConfig {
     pub stakes: BTreeMap<TxHash, StakeDoc>,
     /// Initial fee pool. Half-life is approximately 15 days.
     pub init_fee_pool: CoinValue,
+    /// Consensus parameters. If not given, the default parameters for the network are used.
+    #[serde(default)]
+    pub params: Option<ConsensusParams>,
 }
 
-impl GenesisConfig { 
+impl GenesisConfig {
     /// The "standard" mainnet genesis.
     pub fn std_mainnet() -> Self {
         Self {
@@ -57,6 +60,7 @@ impl GenesisConfig {
                 })
                 .collect(),
             init_fee_pool: CoinValue::from_millions(6553600u64), // subsidy, decreasing rapidly
+            params: None,
         }
     }
 
@@ -91,6 +95,7 @@ impl GenesisConfig {
             })
             .collect(),
             init_fee_pool: (1 << 64).into(),
+            params: None,
         }
     }
 
@@ -99,6 +104,9 @@ impl GenesisConfig {
         let empty_tree = db.get_tree(HashVal::default().0).unwrap();
         let mut new_state = State {
             network: self.network,
+            params: self
+                .params
+                .unwrap_or_else(|| ConsensusParams::for_network(self.network)),
             height: 0.into(),
             history: SmtMapping::new(empty_tree.clone()),
             coins: CoinMapping::new(empty_tree.clone()),
@@ -1,19 +1,34 @@
 #![allow(clippy::upper_case_acronyms)]
 #![doc = include_str!("../README.md")]
 
+mod blocktree;
+mod builder;
+mod fraud;
 mod genesis;
 pub mod melvm;
+mod mempool;
+mod migration;
+mod params;
 mod smtmapping;
+mod smtnode;
 mod stake;
 mod state;
 pub mod stats;
 mod testing;
 pub mod tip_heights;
+mod witness;
 
+pub use crate::blocktree::*;
+pub use crate::builder::*;
+pub use crate::fraud::*;
 pub use crate::genesis::*;
+pub use crate::mempool::*;
+pub use crate::migration::*;
+pub use crate::params::*;
 pub use crate::smtmapping::*;
 pub use crate::state::melmint::*;
 pub use crate::state::*;
+pub use crate::witness::*;
 
 #[cfg(test)]
 #[macro_use]
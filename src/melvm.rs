@@ -36,6 +36,17 @@ pub enum AddrParseError {
     CannotParse,
 }
 
+#[derive(Error, Clone, Eq, PartialEq, Debug)]
+/// The reason a covenant rejected a transaction.
+pub enum CovenantFailure {
+    #[error("cannot decode covenant: {0}")]
+    Undecodable(String),
+    #[error("covenant faulted at instruction {pc}")]
+    RuntimeFault { pc: usize },
+    #[error("covenant returned false")]
+    ReturnedFalse,
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize)]
 /// The execution environment of a covenant. Serializable to make use with yaml/toml/etc more convenient.
 pub struct CovenantEnv {
@@ -88,11 +99,32 @@ impl Covenant {
 
     /// Execute a transaction in a [CovenantEnv] to completion and return whether the covenant succeeded.
     pub fn check_opt_env(&self, tx: &Transaction, env: Option<CovenantEnv>) -> bool {
+        self.check_discerning_opt_env(tx, env).is_ok()
+    }
+
+    /// Checks a transaction like [Covenant::check], but returns why the covenant rejected the transaction on failure.
+    pub fn check_discerning(
+        &self,
+        tx: &Transaction,
+        env: CovenantEnv,
+    ) -> Result<(), CovenantFailure> {
+        self.check_discerning_opt_env(tx, Some(env))
+    }
+
+    /// Execute a transaction in a [CovenantEnv] to completion, returning why the covenant failed if it did.
+    pub fn check_discerning_opt_env(
+        &self,
+        tx: &Transaction,
+        env: Option<CovenantEnv>,
+    ) -> Result<(), CovenantFailure> {
         let _timer = STAT_MELVM_RUNTIME_SECS.timer_secs("running covenant");
-        if let Ok(instrs) = self.to_ops() {
-            Executor::new_from_env(instrs, tx.clone(), env).run_to_end()
-        } else {
-            false
+        let instrs = self
+            .to_ops()
+            .map_err(|e| CovenantFailure::Undecodable(e.to_string()))?;
+        match Executor::new_from_env(instrs, tx.clone(), env).run_discerning_to_end() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CovenantFailure::ReturnedFalse),
+            Err(pc) => Err(CovenantFailure::RuntimeFault { pc }),
         }
     }
 
@@ -365,6 +397,31 @@ mod tests {
         assert!(!check_sig_script.check_opt_env(&tx, None));
     }
 
+    #[test]
+    fn check_discerning() {
+        let tx = Transaction::empty_test();
+        assert_eq!(
+            Covenant::always_true().check_discerning_opt_env(&tx, None),
+            Ok(())
+        );
+        let returns_false = Covenant::from_ops(&[OpCode::PushI(0u32.into())]).unwrap();
+        assert_eq!(
+            returns_false.check_discerning_opt_env(&tx, None),
+            Err(CovenantFailure::ReturnedFalse)
+        );
+        // popping from an empty stack faults on the second instruction
+        let faults =
+            Covenant::from_ops(&[OpCode::PushI(1u32.into()), OpCode::Add, OpCode::Noop]).unwrap();
+        assert_eq!(
+            faults.check_discerning_opt_env(&tx, None),
+            Err(CovenantFailure::RuntimeFault { pc: 1 })
+        );
+        assert!(matches!(
+            Covenant(vec![0xee]).check_discerning_opt_env(&tx, None),
+            Err(CovenantFailure::Undecodable(_))
+        ));
+    }
+
     #[quickcheck]
     fn deterministic_execution(bitcode: Vec<u8>) -> bool {
         let ops = Covenant(bitcode).to_ops();
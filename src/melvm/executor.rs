@@ -137,13 +137,17 @@ impl Executor {
 
     /// Execute to the end
     pub fn run_to_end(&mut self) -> bool {
+        self.run_discerning_to_end().unwrap_or(false)
+    }
+
+    /// Execute to the end, returning the program counter of the faulting instruction if execution fails.
+    pub fn run_discerning_to_end(&mut self) -> Result<bool, ProgramCounter> {
         while self.pc < self.instrs.len() {
-            if self.step().is_none() {
-                return false;
-            }
+            let pc = self.pc;
+            self.step().ok_or(pc)?;
         }
 
-        self.stack.pop().map(|f| f.into_bool()).unwrap_or_default()
+        Ok(self.stack.pop().map(|f| f.into_bool()).unwrap_or_default())
     }
 
     /// Execute to the end, without popping.
@@ -0,0 +1,249 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use novasmt::ContentAddrStore;
+use num::BigInt;
+use rustc_hash::FxHashMap;
+use themelio_structs::{CoinID, Transaction, TxHash};
+use thiserror::Error;
+
+use crate::{melvm::covenant_weight_from_bytes, SealedState, State, StateError};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// An error that happens while adding a transaction to a mempool
+pub enum MempoolError {
+    #[error("transaction {0} already in mempool")]
+    Duplicate(TxHash),
+    #[error("input {coin_id:?} already spent by pending transaction {spender}")]
+    Conflict { coin_id: CoinID, spender: TxHash },
+    #[error(transparent)]
+    Invalid(#[from] StateError),
+}
+
+/// A pool of pending transactions, kept as a speculative [State] on top of the latest [SealedState].
+///
+/// Transactions are validated with [State::apply_tx], so the mempool accepts exactly the transactions that a block built on the same state would.
+#[derive(Debug)]
+pub struct Mempool<C: ContentAddrStore> {
+    base: SealedState<C>,
+    provisional: State<C>,
+    txx: BTreeMap<TxHash, Transaction>,
+    // insertion order, so that dependent transactions can be re-applied after their parents
+    order: Vec<TxHash>,
+    spenders: FxHashMap<CoinID, TxHash>,
+}
+
+impl<C: ContentAddrStore> Mempool<C> {
+    /// Creates an empty mempool on top of the given sealed state.
+    pub fn new(base: SealedState<C>) -> Self {
+        let provisional = base.next_state();
+        Self {
+            base,
+            provisional,
+            txx: BTreeMap::new(),
+            order: Vec::new(),
+            spenders: FxHashMap::default(),
+        }
+    }
+
+    /// Returns the sealed state that the mempool builds on.
+    pub fn base(&self) -> &SealedState<C> {
+        &self.base
+    }
+
+    /// Returns the speculative state, with every pending transaction applied.
+    pub fn provisional_state(&self) -> &State<C> {
+        &self.provisional
+    }
+
+    /// Returns the number of pending transactions.
+    pub fn len(&self) -> usize {
+        self.txx.len()
+    }
+
+    /// Returns whether there are no pending transactions.
+    pub fn is_empty(&self) -> bool {
+        self.txx.is_empty()
+    }
+
+    /// Looks up a pending transaction.
+    pub fn lookup(&self, txhash: TxHash) -> Option<&Transaction> {
+        self.txx.get(&txhash)
+    }
+
+    /// Returns the pending transaction spending the given coin, if any.
+    pub fn spender(&self, coin_id: CoinID) -> Option<TxHash> {
+        self.spenders.get(&coin_id).copied()
+    }
+
+    /// Adds a transaction to the mempool, rejecting it if it conflicts with a pending transaction or is not valid on top of the speculative state.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), MempoolError> {
+        let txhash = tx.hash_nosigs();
+        if self.txx.contains_key(&txhash) {
+            return Err(MempoolError::Duplicate(txhash));
+        }
+        if let Some((coin_id, spender)) = tx
+            .inputs
+            .iter()
+            .find_map(|input| Some((*input, *self.spenders.get(input)?)))
+        {
+            return Err(MempoolError::Conflict { coin_id, spender });
+        }
+        self.provisional.apply_tx(tx)?;
+        self.insert_unchecked(tx.clone());
+        Ok(())
+    }
+
+    /// Returns the pending transactions, ordered by decreasing tip per unit of weight.
+    pub fn transactions_by_fee_rate(&self) -> Vec<&Transaction> {
+        let fee_multiplier = self.provisional.fee_multiplier;
+        let mut txx: Vec<_> = self
+            .txx
+            .values()
+            .map(|tx| (tip_and_weight(tx, fee_multiplier), tx))
+            .collect();
+        txx.sort_by(|(a, _), (b, _)| compare_fee_rates(*b, *a));
+        txx.into_iter().map(|(_, tx)| tx).collect()
+    }
+
+    /// Moves the mempool on top of a new sealed state, usually the next block. Transactions included in the block are dropped, and the rest are re-validated in the order they were added.
+    ///
+    /// Returns the transactions that were evicted because they are no longer valid, for example because the block spent their inputs.
+    pub fn rebase(&mut self, new_base: SealedState<C>) -> Vec<(TxHash, StateError)> {
+        let included = &new_base.inner_ref().transactions;
+        let pending: Vec<Transaction> = std::mem::take(&mut self.order)
+            .into_iter()
+            .filter(|txhash| !included.contains_key(txhash))
+            .filter_map(|txhash| self.txx.remove(&txhash))
+            .collect();
+        self.txx.clear();
+        self.spenders.clear();
+        self.provisional = new_base.next_state();
+        self.base = new_base;
+
+        let mut evicted = vec![];
+        let results = self.provisional.apply_tx_batch_lenient(&pending);
+        for (tx, (txhash, result)) in pending.into_iter().zip(results) {
+            match result {
+                Ok(()) => self.insert_unchecked(tx),
+                Err(err) => {
+                    log::debug!("evicting {} from mempool: {:?}", txhash, err);
+                    evicted.push((txhash, err))
+                }
+            }
+        }
+        evicted
+    }
+
+    fn insert_unchecked(&mut self, tx: Transaction) {
+        let txhash = tx.hash_nosigs();
+        for input in tx.inputs.iter() {
+            self.spenders.insert(*input, txhash);
+        }
+        self.order.push(txhash);
+        self.txx.insert(txhash, tx);
+    }
+}
+
+/// Returns the tip and weight of a transaction, given the fee multiplier.
+pub(crate) fn tip_and_weight(tx: &Transaction, fee_multiplier: u128) -> (u128, u128) {
+    let min_fee = tx.base_fee(fee_multiplier, 0, covenant_weight_from_bytes);
+    (
+        tx.fee.0.saturating_sub(min_fee.0),
+        tx.weight(covenant_weight_from_bytes),
+    )
+}
+
+/// Compares two (tip, weight) pairs by tip per unit of weight.
+pub(crate) fn compare_fee_rates(a: (u128, u128), b: (u128, u128)) -> Ordering {
+    (BigInt::from(a.0) * BigInt::from(b.1)).cmp(&(BigInt::from(b.0) * BigInt::from(a.1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tap::Tap;
+    use themelio_structs::CoinValue;
+
+    use crate::{
+        testing::functions::{create_state, valid_txx},
+        Mempool, MempoolError,
+    };
+
+    #[test]
+    fn conflicts_and_duplicates() {
+        let mut mempool = Mempool::new(create_state(&HashMap::new(), 0).seal(None));
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        assert_eq!(mempool.len(), txx.len());
+        assert_eq!(
+            mempool.apply_transaction(&txx[0]),
+            Err(MempoolError::Duplicate(txx[0].hash_nosigs()))
+        );
+        let double_spend = txx[1].clone().tap_mut(|tx| tx.fee += CoinValue(1));
+        assert_eq!(
+            mempool.apply_transaction(&double_spend),
+            Err(MempoolError::Conflict {
+                coin_id: txx[1].inputs[0],
+                spender: txx[1].hash_nosigs()
+            })
+        );
+        assert_eq!(mempool.provisional_state().transactions.len(), txx.len());
+    }
+
+    #[test]
+    fn fee_rate_ordering() {
+        let mut mempool = Mempool::new(create_state(&HashMap::new(), 0).seal(None));
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        for tx in txx.iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        let fee_multiplier = mempool.provisional_state().fee_multiplier;
+        let ordered = mempool.transactions_by_fee_rate();
+        assert_eq!(ordered.len(), txx.len());
+        for pair in ordered.windows(2) {
+            assert_ne!(
+                super::compare_fee_rates(
+                    super::tip_and_weight(pair[0], fee_multiplier),
+                    super::tip_and_weight(pair[1], fee_multiplier)
+                ),
+                std::cmp::Ordering::Less
+            );
+        }
+    }
+
+    #[test]
+    fn rebase_evicts() {
+        let base = create_state(&HashMap::new(), 0).seal(None);
+        let mut mempool = Mempool::new(base.clone());
+        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let txx = valid_txx((pk, sk));
+        // each transaction spends the previous one's output, so none can be added before the first
+        assert!(matches!(
+            mempool.apply_transaction(&txx[1]),
+            Err(MempoolError::Invalid(_))
+        ));
+        for tx in txx[..10].iter() {
+            mempool.apply_transaction(tx).unwrap();
+        }
+        // a block that contains the first five transactions, as well as a conflicting spend of the sixth's input
+        let mut next = base.next_state();
+        next.apply_tx_batch(&txx[..5]).unwrap();
+        let conflicting = txx[5]
+            .clone()
+            .tap_mut(|tx| {
+                tx.fee += CoinValue(1);
+                tx.outputs[0].value -= CoinValue(1);
+                tx.sigs.clear();
+            })
+            .signed_ed25519(sk);
+        next.apply_tx(&conflicting).unwrap();
+        let evicted = mempool.rebase(next.seal(None));
+
+        assert_eq!(mempool.len(), 0);
+        assert_eq!(evicted.len(), 5);
+        assert_eq!(evicted[0].0, txx[5].hash_nosigs());
+    }
+}
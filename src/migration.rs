@@ -0,0 +1,398 @@
+use std::{collections::BTreeMap, ops::ControlFlow};
+
+use novasmt::ContentAddrStore;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use themelio_structs::{CoinDataHeight, CoinID, Denom, PoolKey};
+use thiserror::Error;
+use tmelcrypt::{HashVal, Hashable};
+
+use crate::State;
+
+/// A migration that rewrites part of a state, one entry of a source tree at a time.
+///
+/// Migrations are run by [run_migration], which reports [MigrationCheckpoint]s from which an interrupted migration can be resumed.
+pub trait Migration<C: ContentAddrStore> {
+    /// A short name, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// The tree whose entries are migrated. It is read once at the start, so the migration may write to it.
+    fn source(&self, state: &State<C>) -> novasmt::Tree<C>;
+
+    /// The tree that the migration writes to.
+    fn target(&self, state: &State<C>) -> novasmt::Tree<C>;
+
+    /// Replaces the tree that the migration writes to, when resuming.
+    fn set_target(&self, state: &mut State<C>, target: novasmt::Tree<C>);
+
+    /// Migrates one entry of the source tree.
+    fn migrate_entry(&self, state: &mut State<C>, key: [u8; 32], value: &[u8]);
+}
+
+/// The progress of a migration, from which it can be resumed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationCheckpoint {
+    /// Root of the source tree.
+    pub source_root: HashVal,
+    /// Root of the target tree after migrating the first `processed` entries.
+    pub target_root: HashVal,
+    /// Number of entries of the source tree already migrated.
+    pub processed: u64,
+    /// Total number of entries in the source tree.
+    pub total: u64,
+}
+
+impl MigrationCheckpoint {
+    /// Returns true iff every entry has been migrated.
+    pub fn is_done(&self) -> bool {
+        self.processed >= self.total
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// An error that stops a migration
+pub enum MigrationError {
+    #[error("checkpoint is for source tree {expected}, but the state has {actual}")]
+    SourceMismatch { expected: HashVal, actual: HashVal },
+    #[error("migration interrupted after {} of {} entries", .0.processed, .0.total)]
+    Interrupted(MigrationCheckpoint),
+}
+
+/// Runs a migration on the state, starting from scratch or resuming from a checkpoint.
+///
+/// `on_checkpoint` is called every `checkpoint_interval` entries and once at the end. Returning [ControlFlow::Break] from it stops the migration with [MigrationError::Interrupted], leaving a partially migrated state that should be discarded.
+pub fn run_migration<C: ContentAddrStore, M: Migration<C>>(
+    state: &mut State<C>,
+    migration: &M,
+    resume_from: Option<&MigrationCheckpoint>,
+    checkpoint_interval: u64,
+    mut on_checkpoint: impl FnMut(&MigrationCheckpoint) -> ControlFlow<()>,
+) -> Result<(), MigrationError> {
+    let source = migration.source(state);
+    let source_root = HashVal(source.root_hash());
+    let mut checkpoint = match resume_from {
+        Some(checkpoint) => {
+            if checkpoint.source_root != source_root {
+                return Err(MigrationError::SourceMismatch {
+                    expected: checkpoint.source_root,
+                    actual: source_root,
+                });
+            }
+            let target = source
+                .database()
+                .get_tree(checkpoint.target_root.0)
+                .unwrap();
+            migration.set_target(state, target);
+            *checkpoint
+        }
+        None => MigrationCheckpoint {
+            source_root,
+            target_root: HashVal(migration.target(state).root_hash()),
+            processed: 0,
+            total: source.count(),
+        },
+    };
+    log::info!(
+        "running migration {}, {} of {} entries done",
+        migration.name(),
+        checkpoint.processed,
+        checkpoint.total
+    );
+    let checkpoint_interval = checkpoint_interval.max(1);
+    for (key, value) in source.iter().skip(checkpoint.processed as usize) {
+        migration.migrate_entry(state, key, &value);
+        checkpoint.processed += 1;
+        if checkpoint.processed % checkpoint_interval == 0 && !checkpoint.is_done() {
+            checkpoint.target_root = HashVal(migration.target(state).root_hash());
+            if on_checkpoint(&checkpoint).is_break() {
+                return Err(MigrationError::Interrupted(checkpoint));
+            }
+        }
+    }
+    checkpoint.target_root = HashVal(migration.target(state).root_hash());
+    let _ = on_checkpoint(&checkpoint);
+    Ok(())
+}
+
+/// The TIP-906 migration, which backfills the coin count of every address into the coin tree.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tip906Migration;
+
+impl<C: ContentAddrStore> Migration<C> for Tip906Migration {
+    fn name(&self) -> &'static str {
+        "tip-906"
+    }
+
+    fn source(&self, state: &State<C>) -> novasmt::Tree<C> {
+        state.coins.inner().clone()
+    }
+
+    fn target(&self, state: &State<C>) -> novasmt::Tree<C> {
+        state.coins.inner().clone()
+    }
+
+    fn set_target(&self, state: &mut State<C>, target: novasmt::Tree<C>) {
+        state.coins.set_inner(target);
+    }
+
+    fn migrate_entry(&self, state: &mut State<C>, _key: [u8; 32], value: &[u8]) {
+        let cdh: CoinDataHeight =
+            stdcode::deserialize(value).expect("pre-tip906 coin tree has non-cdh elements?!");
+        let old_count = state.coins.coin_count(cdh.coin_data.covhash);
+        state
+            .coins
+            .insert_coin_count(cdh.coin_data.covhash, old_count + 1);
+    }
+}
+
+/// A migration that builds the key index of the pool mapping of an existing state.
+///
+/// The pool mapping only stores hashed keys, so the migration is given candidate keys, such as those of pools created by past deposits, and indexes the ones in the mapping. The built-in pools are always candidates.
+#[derive(Clone, Debug, Default)]
+pub struct PoolKeyBackfill {
+    candidates: BTreeMap<[u8; 32], PoolKey>,
+}
+
+impl PoolKeyBackfill {
+    /// Creates a backfill of the given candidate keys, as well as the keys of the built-in pools.
+    pub fn new(candidates: impl IntoIterator<Item = PoolKey>) -> Self {
+        let builtins = [
+            PoolKey::mel_and(Denom::Sym),
+            PoolKey::mel_and(Denom::Erg),
+            PoolKey::new(Denom::Erg, Denom::Sym),
+        ];
+        Self {
+            candidates: builtins
+                .into_iter()
+                .chain(candidates)
+                .map(|key| (key.stdcode().hash().0, key))
+                .collect(),
+        }
+    }
+}
+
+impl<C: ContentAddrStore> Migration<C> for PoolKeyBackfill {
+    fn name(&self) -> &'static str {
+        "pool-key-backfill"
+    }
+
+    fn source(&self, state: &State<C>) -> novasmt::Tree<C> {
+        state.pools.mapping.clone()
+    }
+
+    fn target(&self, state: &State<C>) -> novasmt::Tree<C> {
+        let root = state
+            .pools
+            .key_index()
+            .map_or([0; 32], |index| index.root_hash().0);
+        state.pools.mapping.database().get_tree(root).unwrap()
+    }
+
+    fn set_target(&self, state: &mut State<C>, target: novasmt::Tree<C>) {
+        state.pools.restore_key_index(HashVal(target.root_hash()));
+    }
+
+    fn migrate_entry(&self, state: &mut State<C>, key: [u8; 32], _value: &[u8]) {
+        if let Some(pool) = self.candidates.get(&key) {
+            state.pools.remember_key(pool);
+        }
+    }
+}
+
+/// A migration that builds the address index of an existing state.
+///
+/// The coin tree only stores hashed coin IDs, so the migration looks them up with the given function. Coins it cannot find are left out of the index.
+pub struct AddressIndexBackfill<F: Fn(HashVal) -> Option<CoinID>> {
+    lookup: F,
+}
+
+impl<F: Fn(HashVal) -> Option<CoinID>> AddressIndexBackfill<F> {
+    /// Creates a backfill that finds coin IDs with the given function.
+    pub fn new(lookup: F) -> Self {
+        Self { lookup }
+    }
+}
+
+impl<C: ContentAddrStore, F: Fn(HashVal) -> Option<CoinID>> Migration<C>
+    for AddressIndexBackfill<F>
+{
+    fn name(&self) -> &'static str {
+        "address-index-backfill"
+    }
+
+    fn source(&self, state: &State<C>) -> novasmt::Tree<C> {
+        state.coins.inner().clone()
+    }
+
+    fn target(&self, state: &State<C>) -> novasmt::Tree<C> {
+        let root = state
+            .coins
+            .address_index()
+            .map_or([0; 32], |index| index.root_hash().0);
+        state.coins.inner().database().get_tree(root).unwrap()
+    }
+
+    fn set_target(&self, state: &mut State<C>, target: novasmt::Tree<C>) {
+        state
+            .coins
+            .restore_address_index(HashVal(target.root_hash()));
+    }
+
+    fn migrate_entry(&self, state: &mut State<C>, key: [u8; 32], value: &[u8]) {
+        // entries that are not coins, such as the coin counts, do not decode
+        let cdh: CoinDataHeight = match stdcode::deserialize(value) {
+            Ok(cdh) => cdh,
+            Err(_) => return,
+        };
+        if let Some(id) = (self.lookup)(HashVal(key)) {
+            if id.stdcode().hash().0 == key {
+                state.coins.index_existing(id, &cdh);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use novasmt::{Database, InMemoryCas};
+    use stdcode::StdcodeSerializeExt;
+    use tap::Tap;
+    use themelio_structs::{BlockHeight, CoinID, Denom, NetID, PoolKey, Transaction};
+    use tmelcrypt::{HashVal, Hashable};
+
+    use crate::{
+        melvm::Covenant,
+        run_migration,
+        testing::functions::{create_state, faucet_tx},
+        AddressIndexBackfill, ConsensusParams, GenesisConfig, MappingKey, MigrationError,
+        PoolKeyBackfill, Tip906Migration,
+    };
+
+    #[test]
+    fn resumed_tip906_migration_matches() {
+        let db = Database::new(InMemoryCas::default());
+        let params = ConsensusParams::latest().tap_mut(|p| p.tip_906 = BlockHeight(1));
+        let mut state = GenesisConfig::std_testnet()
+            .tap_mut(|g| {
+                g.network = NetID::Custom02;
+                g.params = Some(params);
+            })
+            .realize(&db);
+        let faucets: Vec<Transaction> = (0..50u8)
+            .map(|i| {
+                faucet_tx(i).tap_mut(|tx| tx.outputs[0].covhash = Covenant(vec![i % 7]).hash())
+            })
+            .collect();
+        state.apply_tx_batch(&faucets).unwrap();
+        let sealed = state.seal(None);
+        let expected = sealed.next_state();
+        assert_eq!(expected.coins.coin_count(Covenant(vec![0]).hash()), 8);
+
+        // interrupt the migration twice, then finish it
+        let mut checkpoint = None;
+        for _ in 0..2 {
+            let err = sealed
+                .next_state_resumable(checkpoint.as_ref(), 10, |_| ControlFlow::Break(()))
+                .unwrap_err();
+            match err {
+                MigrationError::Interrupted(cp) => checkpoint = Some(cp),
+                err => panic!("unexpected error {:?}", err),
+            }
+        }
+        assert_eq!(checkpoint.unwrap().processed, 20);
+        let mut reports = vec![];
+        let resumed = sealed
+            .next_state_resumable(checkpoint.as_ref(), 10, |cp| {
+                reports.push(*cp);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(resumed.coins.root_hash(), expected.coins.root_hash());
+        assert!(reports.last().unwrap().is_done());
+
+        // the address index can be backfilled in resumable steps as well
+        let ids: Vec<CoinID> = faucets
+            .iter()
+            .map(|tx| tx.output_coinid(0))
+            .chain([CoinID::zero_zero()])
+            .collect();
+        let backfill = AddressIndexBackfill::new(|hash| {
+            ids.iter().copied().find(|id| id.stdcode().hash() == hash)
+        });
+        let mut state = expected.clone();
+        let err = run_migration(&mut state, &backfill, None, 20, |_| ControlFlow::Break(()));
+        let index_checkpoint = match err {
+            Err(MigrationError::Interrupted(cp)) => cp,
+            err => panic!("unexpected result {:?}", err),
+        };
+        let mut state = expected.clone();
+        run_migration(&mut state, &backfill, Some(&index_checkpoint), 20, |_| {
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        let mut indexed = expected.clone();
+        indexed.coins.enable_address_index(ids.iter().copied());
+        assert_eq!(
+            state.coins.address_index().unwrap().root_hash(),
+            indexed.coins.address_index().unwrap().root_hash()
+        );
+
+        // a checkpoint for a different source tree is rejected
+        let mut state = sealed.inner_ref().clone();
+        let bad = checkpoint
+            .unwrap()
+            .tap_mut(|cp| cp.source_root = HashVal([1; 32]));
+        assert!(matches!(
+            run_migration(&mut state, &Tip906Migration, Some(&bad), 10, |_| {
+                ControlFlow::Continue(())
+            }),
+            Err(MigrationError::SourceMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn pool_key_backfill() {
+        // without a key index, the pool mapping only knows hashed keys
+        let sealed = create_state(&Default::default(), 0).seal(None);
+        let mut state = sealed.inner_ref().clone();
+        let hashed: Vec<_> = state.pools.iter().map(|(key, _)| key).collect();
+        assert_eq!(hashed.len(), 3);
+        assert!(hashed
+            .iter()
+            .all(|key| matches!(key, MappingKey::Hashed(_))));
+
+        // the backfill indexes them without changing the pools
+        let mut index_checkpoint = None;
+        run_migration(&mut state, &PoolKeyBackfill::new([]), None, 10, |cp| {
+            index_checkpoint = Some(*cp);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(
+            state.pools.root_hash(),
+            sealed.inner_ref().pools.root_hash()
+        );
+        let mut keys: Vec<PoolKey> = state
+            .pools
+            .iter()
+            .filter_map(|(key, _)| key.known())
+            .collect();
+        keys.sort();
+        assert_eq!(keys.len(), 3);
+
+        // the index can be restored onto the sealed state, and keeps up with new pools
+        let index_root = state.pools.key_index().unwrap().root_hash();
+        assert_eq!(index_checkpoint.unwrap().target_root, index_root);
+        let restored = sealed.with_pool_key_index(index_root);
+        let mut next = restored.next_state();
+        let pool = PoolKey::new(Denom::Sym, Denom::Custom(HashVal([7; 32]).into()));
+        let pool_state = next.pools.val_iter().next().unwrap();
+        next.pools.insert(pool, pool_state);
+        assert!(next
+            .pools
+            .iter()
+            .all(|(key, _)| matches!(key, MappingKey::Known(_))));
+    }
+}
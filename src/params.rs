@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use themelio_structs::{BlockHeight, NetID, TxHash};
+
+use crate::tip_heights::{
+    TIP_901_HEIGHT, TIP_902_HEIGHT, TIP_906_HEIGHT, TIP_908_HEIGHT, TIP_909A_HEIGHT,
+    TIP_909_HEIGHT, TIP_911_HEIGHT,
+};
+
+/// Default maximum block weight.
+const DEFAULT_MAX_BLOCK_WEIGHT: u128 = 100_000_000;
+
+/// Default maximum number of transactions in a block.
+const DEFAULT_MAX_BLOCK_TXX: u64 = 100_000;
+
+/// The consensus rules of a network: the height at which every rule change activates, as well as the legacy exceptions needed to replay the network's history.
+///
+/// Mainnet and testnet have their own parameter sets; every other network activates all rule changes from genesis by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusParams {
+    /// TIP 901: change fee multiplier calculation
+    pub tip_901: BlockHeight,
+    /// TIP 902: introduce non-MEL/non-MEL pools
+    pub tip_902: BlockHeight,
+    /// TIP 906: coin count commitments
+    pub tip_906: BlockHeight,
+    /// TIP 908: dense merkle trees for transactions
+    pub tip_908: BlockHeight,
+    /// TIP 909: tokenomics
+    pub tip_909: BlockHeight,
+    /// TIP 909a: tokenomics bugfix
+    pub tip_909a: BlockHeight,
+    /// TIP 911: slippage-protected swaps
+    #[serde(default = "default_tip_911")]
+    pub tip_911: BlockHeight,
+
+    /// Below this height, malformed stake transactions are let through without creating a stake.
+    pub legacy_stake_rules_until: BlockHeight,
+    /// Below this height, staked coins are not locked and can be spent.
+    pub legacy_unlocked_stakes_until: BlockHeight,
+    /// Below this height, liquidity deposits are processed with the old rules that lead to the inflation bug.
+    pub legacy_deposit_rules_until: BlockHeight,
+
+    /// Whether faucet transactions are allowed at all.
+    pub allow_faucet: bool,
+    /// A faucet transaction that is accepted even if faucets are not allowed.
+    pub faucet_exception: Option<TxHash>,
+    /// The minimum age, in blocks, of the coin spent by a DoscMint transaction.
+    pub doscmint_min_age: u64,
+
+    /// The height from which the block size limits below are enforced.
+    #[serde(default = "default_block_size_limit_height")]
+    pub block_size_limit_height: BlockHeight,
+    /// The maximum total weight of the transactions in a block.
+    #[serde(default = "default_max_block_weight")]
+    pub max_block_weight: u128,
+    /// The maximum number of transactions in a block.
+    #[serde(default = "default_max_block_txx")]
+    pub max_block_txx: u64,
+}
+
+fn default_tip_911() -> BlockHeight {
+    TIP_911_HEIGHT
+}
+
+fn default_block_size_limit_height() -> BlockHeight {
+    BlockHeight(u64::MAX)
+}
+
+fn default_max_block_weight() -> u128 {
+    DEFAULT_MAX_BLOCK_WEIGHT
+}
+
+fn default_max_block_txx() -> u64 {
+    DEFAULT_MAX_BLOCK_TXX
+}
+
+impl ConsensusParams {
+    /// The parameters of the mainnet.
+    pub fn mainnet() -> Self {
+        Self {
+            tip_901: TIP_901_HEIGHT,
+            tip_902: TIP_902_HEIGHT,
+            tip_906: TIP_906_HEIGHT,
+            tip_908: TIP_908_HEIGHT,
+            tip_909: TIP_909_HEIGHT,
+            tip_909a: TIP_909A_HEIGHT,
+            tip_911: TIP_911_HEIGHT,
+
+            legacy_stake_rules_until: BlockHeight(500000),
+            legacy_unlocked_stakes_until: BlockHeight(900000),
+            legacy_deposit_rules_until: BlockHeight(978392),
+
+            allow_faucet: false,
+            // exception to be bug-compatible with the one guy who exploited the inflation bug
+            faucet_exception: Some(
+                "30a60b20830f000f755b70c57c998553a303cc11f8b1f574d5e9f7e26b645d8b"
+                    .parse()
+                    .unwrap(),
+            ),
+            doscmint_min_age: 100,
+
+            block_size_limit_height: BlockHeight(u64::MAX),
+            max_block_weight: DEFAULT_MAX_BLOCK_WEIGHT,
+            max_block_txx: DEFAULT_MAX_BLOCK_TXX,
+        }
+    }
+
+    /// The parameters of the testnet, which activated every TIP up to TIP 909a at genesis but shares the mainnet's legacy bugs.
+    pub fn testnet() -> Self {
+        Self {
+            tip_911: TIP_911_HEIGHT,
+            legacy_stake_rules_until: BlockHeight(500000),
+            legacy_unlocked_stakes_until: BlockHeight(900000),
+            legacy_deposit_rules_until: BlockHeight(978392),
+            block_size_limit_height: BlockHeight(u64::MAX),
+            ..Self::latest()
+        }
+    }
+
+    /// Parameters with every rule change active from genesis and no legacy exceptions.
+    pub fn latest() -> Self {
+        Self {
+            tip_901: BlockHeight(0),
+            tip_902: BlockHeight(0),
+            tip_906: BlockHeight(0),
+            tip_908: BlockHeight(0),
+            tip_909: BlockHeight(0),
+            tip_909a: BlockHeight(0),
+            tip_911: BlockHeight(0),
+
+            legacy_stake_rules_until: BlockHeight(0),
+            legacy_unlocked_stakes_until: BlockHeight(0),
+            legacy_deposit_rules_until: BlockHeight(0),
+
+            allow_faucet: true,
+            faucet_exception: None,
+            doscmint_min_age: 0,
+
+            block_size_limit_height: BlockHeight(0),
+            max_block_weight: DEFAULT_MAX_BLOCK_WEIGHT,
+            max_block_txx: DEFAULT_MAX_BLOCK_TXX,
+        }
+    }
+
+    /// The default parameters for the given network.
+    pub fn for_network(network: NetID) -> Self {
+        match network {
+            NetID::Mainnet => Self::mainnet(),
+            NetID::Testnet => Self::testnet(),
+            _ => Self::latest(),
+        }
+    }
+
+    /// Returns whether a faucet transaction with the given hash is allowed.
+    pub fn faucet_allowed(&self, txhash: TxHash) -> bool {
+        self.allow_faucet || self.faucet_exception == Some(txhash)
+    }
+}
@@ -1,14 +1,36 @@
+use derivative::Derivative;
 use novasmt::{ContentAddrStore, FullProof};
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use tmelcrypt::HashVal;
 
-use crate::stats::{STAT_SMT_GET_SECS, STAT_SMT_INSERT_SECS};
+use crate::{
+    smtnode,
+    stats::{STAT_SMT_GET_SECS, STAT_SMT_INSERT_SECS},
+};
+
+/// The key of an entry of an [SmtMapping]. The SMT itself only stores hashed keys, so the actual key is only known if the mapping's [KeyIndex] has it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MappingKey<K> {
+    Known(K),
+    Hashed(HashVal),
+}
+
+impl<K> MappingKey<K> {
+    /// Returns the key, if known.
+    pub fn known(self) -> Option<K> {
+        match self {
+            MappingKey::Known(key) => Some(key),
+            MappingKey::Hashed(_) => None,
+        }
+    }
+}
 
 /// SmtMapping is a type-safe, constant-time cloneable, imperative-style interface to a sparse Merkle tree.
 pub struct SmtMapping<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> {
     pub mapping: novasmt::Tree<C>,
+    keys: Option<KeyIndex<C>>,
     _phantom_k: PhantomData<K>,
     _phantom_v: PhantomData<V>,
 }
@@ -25,7 +47,12 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> Clone
     for SmtMapping<C, K, V>
 {
     fn clone(&self) -> Self {
-        SmtMapping::new(self.mapping.clone())
+        SmtMapping {
+            mapping: self.mapping.clone(),
+            keys: self.keys.clone(),
+            _phantom_k: PhantomData,
+            _phantom_v: PhantomData,
+        }
     }
 }
 
@@ -43,6 +70,7 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
     pub fn new(tree: novasmt::Tree<C>) -> Self {
         SmtMapping {
             mapping: tree,
+            keys: None,
             _phantom_k: PhantomData,
             _phantom_v: PhantomData,
         }
@@ -52,7 +80,7 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
         let _timer = STAT_SMT_GET_SECS.timer_secs("smt get");
 
         let key = tmelcrypt::hash_single(&stdcode::serialize(key).unwrap());
-        let (v_bytes, proof) = self.mapping.get_with_proof(key.0);
+        let (v_bytes, proof) = smtnode::get_with_proof(&self.mapping, key.0);
         match v_bytes.len() {
             0 => (None, proof),
             _ => {
@@ -65,10 +93,41 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
     pub fn insert(&mut self, key: K, val: V) {
         let _timer = STAT_SMT_INSERT_SECS.timer_secs("smt insert");
 
+        if self.keys.is_some() {
+            self.remember_key(&key);
+        }
         let key = tmelcrypt::hash_single(&stdcode::serialize(&key).unwrap());
         self.mapping
             .insert(key.0, &stdcode::serialize(&val).unwrap());
     }
+    /// enable_key_index enables the key index, so that iterating over the mapping yields the keys inserted from now on instead of their hashes. Keys already in the mapping can be added with [SmtMapping::remember_key].
+    pub fn enable_key_index(&mut self) {
+        self.keys = Some(KeyIndex {
+            tree: self.mapping.database().get_tree([0; 32]).unwrap(),
+        });
+    }
+    /// restore_key_index restores a previously enabled key index from its root hash.
+    pub fn restore_key_index(&mut self, root: HashVal) {
+        self.keys = Some(KeyIndex {
+            tree: self.mapping.database().get_tree(root.0).unwrap(),
+        });
+    }
+    /// key_index returns the key index, if enabled.
+    pub fn key_index(&self) -> Option<&KeyIndex<C>> {
+        self.keys.as_ref()
+    }
+    /// remember_key adds a key to the key index, enabling it if needed.
+    pub fn remember_key(&mut self, key: &K) {
+        if self.keys.is_none() {
+            self.enable_key_index();
+        }
+        let keys = &mut self.keys.as_mut().unwrap().tree;
+        let key_bytes = stdcode::serialize(key).unwrap();
+        let hashed_key = tmelcrypt::hash_single(&key_bytes).0;
+        if keys.get(hashed_key).is_empty() {
+            keys.insert(hashed_key, &key_bytes);
+        }
+    }
     /// delete deletes a mapping, replacing the mapping with a mapping to the empty bytestring
     pub fn delete(&mut self, key: &K) {
         let _timer = STAT_SMT_INSERT_SECS.timer_secs("smt delete");
@@ -86,4 +145,39 @@ impl<C: ContentAddrStore, K: Serialize, V: Serialize + DeserializeOwned> SmtMapp
             .iter()
             .map(|(_, v)| stdcode::deserialize::<V>(&v).unwrap())
     }
+
+    /// iter returns an iterator over the keys and values. Entries whose keys are not in the key index come with their hashed keys instead.
+    pub fn iter(&'_ self) -> impl Iterator<Item = (MappingKey<K>, V)> + '_
+    where
+        K: DeserializeOwned,
+    {
+        self.mapping
+            .iter()
+            .map(move |(k, v)| (self.resolve_key(k), stdcode::deserialize::<V>(&v).unwrap()))
+    }
+
+    /// Looks up the key with the given hash in the key index.
+    pub(crate) fn resolve_key(&self, hashed_key: [u8; 32]) -> MappingKey<K>
+    where
+        K: DeserializeOwned,
+    {
+        self.keys
+            .as_ref()
+            .and_then(|keys| stdcode::deserialize::<K>(&keys.tree.get(hashed_key)).ok())
+            .map_or(MappingKey::Hashed(HashVal(hashed_key)), MappingKey::Known)
+    }
+}
+
+/// An index from the hashed keys of an [SmtMapping] to the keys themselves, maintained by the mapping once enabled. It is not part of the consensus state.
+#[derive(Debug, Derivative)]
+#[derivative(Clone(bound = ""))]
+pub struct KeyIndex<C: ContentAddrStore> {
+    tree: novasmt::Tree<C>,
+}
+
+impl<C: ContentAddrStore> KeyIndex<C> {
+    /// Root hash of the index.
+    pub fn root_hash(&self) -> HashVal {
+        HashVal(self.tree.root_hash())
+    }
 }
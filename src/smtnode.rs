@@ -0,0 +1,288 @@
+use novasmt::{hash_data, hash_node, ContentAddrStore, Database, FullProof};
+
+/// A raw SMT node, as stored in the database under its hash. `novasmt` does not expose its node encoding, so this mirrors it; the tests pin it against the trees that `novasmt` builds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RawNode<'a> {
+    /// A single key-value pair, at some height in nibbles.
+    Single {
+        height: u8,
+        key: [u8; 32],
+        value: &'a [u8],
+    },
+    /// A node with 16 children, at some height in nibbles.
+    ///
+    /// The count of entries below the node is not covered by the node's hash, so it cannot be trusted in a node that came from elsewhere.
+    Hexary {
+        height: u8,
+        count: u64,
+        children: Box<[[u8; 32]; 16]>,
+    },
+}
+
+impl<'a> RawNode<'a> {
+    /// Decodes a raw node, or returns `None` if it is malformed.
+    pub fn decode(node: &'a [u8]) -> Option<Self> {
+        match *node.first()? {
+            0 => {
+                if node.len() < 2 + 32 {
+                    return None;
+                }
+                Some(Self::Single {
+                    height: node[1],
+                    key: node[2..34].try_into().unwrap(),
+                    value: &node[34..],
+                })
+            }
+            height => {
+                if node.len() != 1 + 8 + 32 * 16 {
+                    return None;
+                }
+                let mut children = Box::new([[0; 32]; 16]);
+                for (child, chunk) in children.iter_mut().zip(node[9..].chunks_exact(32)) {
+                    *child = chunk.try_into().unwrap();
+                }
+                Some(Self::Hexary {
+                    height,
+                    count: u64::from_le_bytes(node[1..9].try_into().unwrap()),
+                    children,
+                })
+            }
+        }
+    }
+
+    /// Encodes the node the way it is stored in the database.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Single { height, key, value } => {
+                let mut accum = vec![0, *height];
+                accum.extend_from_slice(key);
+                accum.extend_from_slice(value);
+                accum
+            }
+            Self::Hexary {
+                height,
+                count,
+                children,
+            } => {
+                let mut accum = vec![*height];
+                accum.extend_from_slice(&count.to_le_bytes());
+                for child in children.iter() {
+                    accum.extend_from_slice(child);
+                }
+                accum
+            }
+        }
+    }
+
+    /// Computes the hash of the node, under which it is stored.
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Self::Single { height, key, value } => singleton_root(*height as usize * 4, key, value),
+            Self::Hexary { children, .. } => hexary_levels(children)[0][0],
+        }
+    }
+}
+
+/// Returns the bit of the key at the given position, from the most significant one.
+fn key_bit(key: &[u8; 32], position: usize) -> bool {
+    (key[position / 8] >> (7 - position % 8)) & 1 == 1
+}
+
+/// Returns the root of a subtree with the given number of levels that holds a single entry.
+fn singleton_root(levels: usize, key: &[u8; 32], value: &[u8]) -> [u8; 32] {
+    let mut hash = hash_data(value);
+    for i in 0..levels.min(256) {
+        if key_bit(key, 255 - i) {
+            hash = hash_node([0; 32], hash);
+        } else {
+            hash = hash_node(hash, [0; 32]);
+        }
+    }
+    hash
+}
+
+/// Returns the levels of the binary tree inside a hexary node, from its root down to its children.
+fn hexary_levels(children: &[[u8; 32]; 16]) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![children.to_vec()];
+    while levels[0].len() > 1 {
+        let above = levels[0]
+            .chunks_exact(2)
+            .map(|pair| hash_node(pair[0], pair[1]))
+            .collect();
+        levels.insert(0, above);
+    }
+    levels
+}
+
+/// Gets a value from an SMT along with a proof, the way `novasmt`'s `Tree::get_with_proof` does.
+///
+/// Unlike `novasmt`, this does not panic if the proof does not verify, as it cannot when a witness stands in placeholders for missing nodes.
+pub(crate) fn get_with_proof<C: ContentAddrStore>(
+    tree: &novasmt::Tree<C>,
+    key: [u8; 32],
+) -> (Vec<u8>, FullProof) {
+    let mut proof = Vec::with_capacity(256);
+    let mut hash = tree.root_hash();
+    let mut depth = 0;
+    let value = loop {
+        if hash == [0; 32] {
+            break vec![];
+        }
+        let raw = tree.storage().get(&hash).expect("dangling pointer");
+        match RawNode::decode(&raw).expect("corrupt SMT node") {
+            RawNode::Single {
+                key: single_key,
+                value,
+                ..
+            } => {
+                let mut position = depth * 4;
+                while position < 256 && key_bit(&key, position) == key_bit(&single_key, position) {
+                    proof.push([0; 32]);
+                    position += 1;
+                }
+                if position == 256 {
+                    // only the bits below this node matter, and they all match
+                    proof.truncate(depth * 4);
+                    break value.to_vec();
+                }
+                proof.push(singleton_root(255 - position, &single_key, value));
+                break vec![];
+            }
+            RawNode::Hexary { children, .. } => {
+                let index = ((key[depth / 2] >> (4 * (1 - depth % 2))) & 0xf) as usize;
+                for (level, hashes) in hexary_levels(&children).iter().skip(1).enumerate() {
+                    proof.push(hashes[(index >> (3 - level)) ^ 1]);
+                }
+                hash = children[index];
+                depth += 1;
+            }
+        }
+    };
+    proof.resize(256, [0; 32]);
+    (value, FullProof(proof))
+}
+
+/// Iterates over the entries of an SMT in the order of their hashed keys, loading nodes only as they are reached.
+pub(crate) struct OrderedIter<C: ContentAddrStore> {
+    db: Database<C>,
+    after: Option<[u8; 32]>,
+    /// Nodes left to visit, with their depths in nibbles and whether they lie on the path to `after`.
+    stack: Vec<([u8; 32], usize, bool)>,
+}
+
+impl<C: ContentAddrStore> OrderedIter<C> {
+    /// Iterates over the entries of the tree whose hashed keys come strictly after `after`, or all of them.
+    pub fn new(tree: &novasmt::Tree<C>, after: Option<[u8; 32]>) -> Self {
+        Self {
+            db: tree.database(),
+            after,
+            stack: vec![(tree.root_hash(), 0, after.is_some())],
+        }
+    }
+}
+
+impl<C: ContentAddrStore> Iterator for OrderedIter<C> {
+    type Item = ([u8; 32], Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((hash, depth, on_path)) = self.stack.pop() {
+            if hash == [0; 32] {
+                continue;
+            }
+            let raw = self.db.storage().get(&hash).expect("dangling pointer");
+            match RawNode::decode(&raw).expect("corrupt SMT node") {
+                RawNode::Single { key, value, .. } => {
+                    if !on_path || Some(key) > self.after {
+                        return Some((key, value.to_vec()));
+                    }
+                }
+                RawNode::Hexary { children, .. } => {
+                    // the children of a node at some depth are indexed by that nibble of the key, from the most significant one
+                    let first = match self.after {
+                        Some(after) if on_path => (after[depth / 2] >> (4 * (1 - depth % 2))) & 0xf,
+                        _ => 0,
+                    } as usize;
+                    for index in (first..16).rev() {
+                        self.stack
+                            .push((children[index], depth + 1, on_path && index == first));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use novasmt::{ContentAddrStore, Database, InMemoryCas};
+
+    use super::{get_with_proof, OrderedIter, RawNode};
+
+    #[test]
+    fn matches_novasmt() {
+        let db = Database::new(InMemoryCas::default());
+        let mut tree = db.get_tree([0; 32]).unwrap();
+        for i in 0u8..50 {
+            tree.insert(novasmt::hash_data(&[i]), &[i; 10]);
+            let stored = db.storage().get(&tree.root_hash()).unwrap();
+            let node = RawNode::decode(&stored).unwrap();
+            // the first entry makes the root a single node, and the second a hexary one
+            match (i, &node) {
+                (0, RawNode::Single { .. }) => {}
+                (0, _) => panic!("root of a single entry is not a single node"),
+                (_, RawNode::Hexary { count, .. }) => assert_eq!(*count, i as u64 + 1),
+                (_, _) => panic!("root of several entries is not a hexary node"),
+            }
+            assert_eq!(node.hash(), tree.root_hash());
+            assert_eq!(node.encode(), stored.to_vec());
+        }
+
+        // the nodes below the root, at every height, match too
+        let mut stack = vec![tree.root_hash()];
+        while let Some(hash) = stack.pop() {
+            let stored = db.storage().get(&hash).unwrap();
+            let node = RawNode::decode(&stored).unwrap();
+            assert_eq!(node.hash(), hash);
+            if let RawNode::Hexary { children, .. } = node {
+                stack.extend(children.iter().filter(|child| **child != [0; 32]));
+            }
+        }
+
+        // entries come in the order of their keys, and can be resumed after any key
+        let mut keys: Vec<[u8; 32]> = tree.iter().map(|(k, _)| k).collect();
+        keys.sort_unstable();
+        let ordered: Vec<[u8; 32]> = OrderedIter::new(&tree, None).map(|(k, _)| k).collect();
+        assert_eq!(ordered, keys);
+        for (i, after) in keys.iter().enumerate() {
+            let rest: Vec<[u8; 32]> = OrderedIter::new(&tree, Some(*after))
+                .map(|(k, _)| k)
+                .collect();
+            assert_eq!(rest, keys[i + 1..]);
+        }
+        let absent = novasmt::hash_data(b"absent");
+        let rest = OrderedIter::new(&tree, Some(absent)).count();
+        assert_eq!(rest, keys.iter().filter(|k| **k > absent).count());
+    }
+
+    #[test]
+    fn proofs_match_novasmt() {
+        let db = Database::new(InMemoryCas::default());
+        let mut tree = db.get_tree([0; 32]).unwrap();
+        for i in 0u8..50 {
+            tree.insert(novasmt::hash_data(&[i]), &[i; 10]);
+        }
+        // present and absent keys, including ones that diverge from a single node deep down
+        let mut near = novasmt::hash_data(&[7]);
+        near[31] ^= 1;
+        let keys = (0u8..60)
+            .map(|i| novasmt::hash_data(&[i]))
+            .chain([near, [0; 32], [0xff; 32]]);
+        for key in keys {
+            let (value, proof) = get_with_proof(&tree, key);
+            let (expected_value, expected_proof) = tree.get_with_proof(key);
+            assert_eq!(value, expected_value.to_vec());
+            assert_eq!(proof.0, expected_proof.0);
+        }
+    }
+}
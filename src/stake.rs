@@ -1,14 +1,84 @@
 #![allow(clippy::float_cmp)]
 
+use std::collections::HashMap;
+
 use crate::SmtMapping;
 use novasmt::ContentAddrStore;
-use themelio_structs::{StakeDoc, TxHash};
-use tmelcrypt::Ed25519PK;
+use themelio_structs::{ConsensusProof, StakeDoc, TxHash};
+use thiserror::Error;
+use tmelcrypt::{Ed25519PK, HashVal};
 
 /// A stake mapping
 pub type StakeMapping<C> = SmtMapping<C, TxHash, StakeDoc>;
 
+#[derive(Error, Debug, PartialEq, Eq)]
+/// An error that happens while verifying a consensus proof
+pub enum ConsensusProofError {
+    #[error("signer {:?} has no stake in epoch {1}", .0)]
+    UnknownSigner(Ed25519PK, u64),
+    #[error("bad signature from {:?}", .0)]
+    BadSignature(Ed25519PK),
+    #[error("insufficient stake (signed {signed} out of {total})")]
+    InsufficientStake { signed: u128, total: u128 },
+}
+
 impl<C: ContentAddrStore> StakeMapping<C> {
+    /// Gets the number of syms staked by a given public key in a given epoch.
+    pub fn votes(&self, epoch: u64, pubkey: Ed25519PK) -> u128 {
+        self.val_iter()
+            .filter(|sdoc| {
+                epoch >= sdoc.e_start && epoch < sdoc.e_post_end && sdoc.pubkey == pubkey
+            })
+            .fold(0u128, |a, sdoc| a.saturating_add(sdoc.syms_staked.0))
+    }
+
+    /// Gets the total number of syms staked in a given epoch.
+    pub fn total_votes(&self, epoch: u64) -> u128 {
+        self.val_iter()
+            .filter(|sdoc| epoch >= sdoc.e_start && epoch < sdoc.e_post_end)
+            .fold(0u128, |a, sdoc| a.saturating_add(sdoc.syms_staked.0))
+    }
+
+    /// Verifies a consensus proof over the given header hash, for a block in the given epoch. Every signature must be valid and come from a staker, and the signers must together hold more than 2/3 of the stake. Returns the number of syms that signed.
+    pub fn verify_cproof(
+        &self,
+        epoch: u64,
+        header_hash: HashVal,
+        cproof: &ConsensusProof,
+    ) -> Result<u128, ConsensusProofError> {
+        let (votes, total) = self.epoch_votes(epoch);
+        let mut signed = 0u128;
+        for (pubkey, sig) in cproof.iter() {
+            let votes = votes.get(pubkey).copied().unwrap_or(0);
+            if votes == 0 {
+                return Err(ConsensusProofError::UnknownSigner(*pubkey, epoch));
+            }
+            if !pubkey.verify(&header_hash.0, sig) {
+                return Err(ConsensusProofError::BadSignature(*pubkey));
+            }
+            signed = signed.saturating_add(votes);
+        }
+        // signed / total > 2/3, computed without overflow
+        if total == 0 || signed <= total / 3 * 2 + (total % 3) * 2 / 3 {
+            return Err(ConsensusProofError::InsufficientStake { signed, total });
+        }
+        Ok(signed)
+    }
+
+    /// Gets the votes of every staker in a given epoch, along with their total, in one pass.
+    fn epoch_votes(&self, epoch: u64) -> (HashMap<Ed25519PK, u128>, u128) {
+        let mut votes: HashMap<Ed25519PK, u128> = HashMap::new();
+        let mut total = 0u128;
+        for sdoc in self.val_iter() {
+            if epoch >= sdoc.e_start && epoch < sdoc.e_post_end {
+                let entry = votes.entry(sdoc.pubkey).or_default();
+                *entry = entry.saturating_add(sdoc.syms_staked.0);
+                total = total.saturating_add(sdoc.syms_staked.0);
+            }
+        }
+        (votes, total)
+    }
+
     /// Gets the voting power, as a floating-point number, for a given public key and a given epoch.
     pub fn vote_power(&self, epoch: u64, pubkey: Ed25519PK) -> f64 {
         let mut total_votes = 1e-50;
@@ -49,9 +119,9 @@ impl<C: ContentAddrStore> StakeMapping<C> {
 
 #[cfg(test)]
 mod tests {
-    use themelio_structs::CoinValue;
+    use themelio_structs::{CoinValue, ConsensusProof};
 
-    use crate::testing::functions::create_state;
+    use crate::{testing::functions::create_state, ConsensusProofError};
 
     use std::collections::HashMap;
 
@@ -222,4 +292,57 @@ mod tests {
             assert_ne!(value.as_ref(), b"");
         });
     }
+
+    #[test]
+    fn test_verify_cproof() {
+        let stakers: HashMap<_, _> = [100u128, 200, 300]
+            .into_iter()
+            .map(|e| (tmelcrypt::ed25519_keygen().1, CoinValue(e)))
+            .collect();
+        let state = create_state(&stakers, 0);
+        let header_hash = tmelcrypt::hash_single(b"hello world");
+        let sign_with = |syms: &[u128]| -> ConsensusProof {
+            stakers
+                .iter()
+                .filter(|(_, v)| syms.contains(&v.0))
+                .map(|(sk, _)| (sk.to_public(), sk.sign(&header_hash.0).into()))
+                .collect()
+        };
+
+        // 500 out of 600 is enough
+        assert_eq!(
+            state
+                .stakes
+                .verify_cproof(0, header_hash, &sign_with(&[200, 300])),
+            Ok(500)
+        );
+        // 400 out of 600 is exactly 2/3, which is not enough
+        assert_eq!(
+            state
+                .stakes
+                .verify_cproof(0, header_hash, &sign_with(&[100, 300])),
+            Err(ConsensusProofError::InsufficientStake {
+                signed: 400,
+                total: 600
+            })
+        );
+
+        // a non-staker
+        let (outsider_pk, outsider_sk) = tmelcrypt::ed25519_keygen();
+        let mut cproof = sign_with(&[100, 200, 300]);
+        cproof.insert(outsider_pk, outsider_sk.sign(&header_hash.0).into());
+        assert_eq!(
+            state.stakes.verify_cproof(0, header_hash, &cproof),
+            Err(ConsensusProofError::UnknownSigner(outsider_pk, 0))
+        );
+
+        // a bad signature
+        let mut cproof = sign_with(&[100, 200, 300]);
+        let (bad_pk, _) = cproof.iter().next().map(|(k, v)| (*k, v.clone())).unwrap();
+        cproof.insert(bad_pk, vec![0u8; 64].into());
+        assert_eq!(
+            state.stakes.verify_cproof(0, header_hash, &cproof),
+            Err(ConsensusProofError::BadSignature(bad_pk))
+        );
+    }
 }
@@ -1,21 +1,32 @@
 mod applytx;
 mod coins;
+mod diff;
+mod fees;
 pub(crate) mod melmint;
+mod proofs;
+mod router;
+mod simulate;
+mod undo;
 
 pub use crate::stake::*;
-use crate::tip_heights::TIP_902_HEIGHT;
 use crate::{
+    melvm::{covenant_weight_from_bytes, CovenantFailure},
+    run_migration,
     smtmapping::*,
-    state::applytx::apply_tx_batch_impl,
-    tip_heights::{
-        TIP_901_HEIGHT, TIP_906_HEIGHT, TIP_908_HEIGHT, TIP_909A_HEIGHT, TIP_909_HEIGHT,
+    state::{
+        applytx::{apply_tx_batch_impl, apply_tx_batch_lenient_impl},
+        fees::next_fee_multiplier,
+        simulate::simulate_tx_batch_impl,
     },
+    ConsensusParams, MigrationCheckpoint, MigrationError, Tip906Migration,
 };
 
 use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 
-use crate::state::melmint::PoolMapping;
+use crate::state::melmint::{MelmintReport, PoolMapping};
 use derivative::Derivative;
 use novasmt::{dense::DenseMerkleTree, ContentAddrStore, Database, InMemoryCas};
 use stdcode::StdcodeSerializeExt;
@@ -27,23 +38,59 @@ use themelio_structs::{
 use thiserror::Error;
 use tmelcrypt::{HashVal, Hashable};
 
-pub use self::coins::CoinMapping;
+pub use self::coins::{AddressIndex, CoinMapping};
+pub use self::diff::{EntryDiff, StateDiff};
+pub use self::fees::{CovenantFee, FeeBreakdown, FeeMultiplierRange};
+pub use self::proofs::{
+    verify_coin_count_proof, verify_coin_count_proof_with_params, verify_coin_proof,
+    verify_header_proof, verify_transaction_proof, verify_transaction_proof_with_params,
+    TransactionProof,
+};
+pub use self::router::{SwapHop, SwapRoute};
+pub use self::simulate::{MelmintRequest, TxEffects};
+pub use self::undo::BlockUndo;
 
 #[derive(Error, Debug, PartialEq, Eq)]
 /// A error that happens while applying a transaction to a state
 pub enum StateError {
     #[error("malformed transaction")]
     MalformedTx,
-    #[error("attempted to spend non-existent coin {:?}", .0)]
-    NonexistentCoin(CoinID),
-    #[error("unbalanced inputs and outputs")]
-    UnbalancedInOut,
-    #[error("insufficient fees (requires {0})")]
-    InsufficientFees(CoinValue),
-    #[error("referenced non-existent script {:?}", .0)]
-    NonexistentScript(Address),
-    #[error("does not satisfy script {:?}", .0)]
-    ViolatesScript(Address),
+    #[error(
+        "transaction {txhash} input {input_index} attempted to spend non-existent coin {coin_id:?}"
+    )]
+    NonexistentCoin {
+        txhash: TxHash,
+        input_index: usize,
+        coin_id: CoinID,
+    },
+    #[error("transaction {txhash} has unbalanced inputs and outputs ({input} {denom:?} in, {output} {denom:?} out)")]
+    UnbalancedInOut {
+        txhash: TxHash,
+        denom: Denom,
+        input: CoinValue,
+        output: CoinValue,
+    },
+    #[error("transaction {txhash} has insufficient fees (requires {required}, paid {paid})")]
+    InsufficientFees {
+        txhash: TxHash,
+        required: CoinValue,
+        paid: CoinValue,
+    },
+    #[error("transaction {txhash} input {input_index} referenced non-existent script {covhash:?}")]
+    NonexistentScript {
+        txhash: TxHash,
+        input_index: usize,
+        covhash: Address,
+    },
+    #[error(
+        "transaction {txhash} input {input_index} does not satisfy script {covhash:?}: {reason}"
+    )]
+    ViolatesScript {
+        txhash: TxHash,
+        input_index: usize,
+        covhash: Address,
+        reason: CovenantFailure,
+    },
     #[error("invalid sequential proof of work")]
     InvalidMelPoW,
     #[error("block has wrong header after applying to previous block")]
@@ -52,12 +99,15 @@ pub enum StateError {
     CoinLocked,
     #[error("duplicate transaction")]
     DuplicateTx,
+    #[error("block too large ({txx} transactions with total weight {weight})")]
+    BlockTooLarge { txx: usize, weight: u128 },
 }
 
 /// World state of the Themelio blockchain
 #[derive(Debug)]
 pub struct State<C: ContentAddrStore> {
     pub network: NetID,
+    pub params: ConsensusParams,
 
     pub height: BlockHeight,
     pub history: SmtMapping<C, BlockHeight, Header>,
@@ -78,6 +128,7 @@ impl<C: ContentAddrStore> Clone for State<C> {
     fn clone(&self) -> Self {
         Self {
             network: self.network,
+            params: self.params,
 
             height: self.height,
             history: self.history.clone(),
@@ -98,32 +149,37 @@ impl<C: ContentAddrStore> Clone for State<C> {
 impl<C: ContentAddrStore> State<C> {
     /// Returns true iff TIP 901 rule changes apply.
     pub fn tip_901(&self) -> bool {
-        self.height >= TIP_901_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_901
     }
 
     /// Returns true iff TIP 902 rule changes apply.
     pub fn tip_902(&self) -> bool {
-        self.height >= TIP_902_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_902
     }
 
     /// Returns true iff TIP 906 rule changes apply.
     pub fn tip_906(&self) -> bool {
-        self.height >= TIP_906_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_906
     }
 
     /// Returns true iff TIP 908 rule changes apply.
     pub fn tip_908(&self) -> bool {
-        self.height >= TIP_908_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_908
     }
 
     /// Returns true iff TIP 909 rule changes apply.
     pub fn tip_909(&self) -> bool {
-        self.height >= TIP_909_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_909
     }
 
     /// Returns true iff TIP 909a rule changes apply.
     pub fn tip_909a(&self) -> bool {
-        self.height >= TIP_909A_HEIGHT || (self.network != NetID::Mainnet)
+        self.height >= self.params.tip_909a
+    }
+
+    /// Returns true iff TIP 911 rule changes apply.
+    pub fn tip_911(&self) -> bool {
+        self.height >= self.params.tip_911
     }
 
     /// Applies a single transaction.
@@ -145,33 +201,55 @@ impl<C: ContentAddrStore> State<C> {
         Ok(())
     }
 
+    /// Applies as many transactions as possible, in order, returning whether each one was accepted. Unlike [State::apply_tx_batch], a bad transaction does not cause the rest of the batch to be rejected.
+    pub fn apply_tx_batch_lenient(
+        &mut self,
+        txx: &[Transaction],
+    ) -> Vec<(TxHash, Result<(), StateError>)> {
+        let (new_state, results) = apply_tx_batch_lenient_impl(self, txx);
+        *self = new_state;
+        results
+    }
+
+    /// Simulates applying a single transaction, without modifying the state.
+    pub fn simulate_tx(&self, tx: &Transaction) -> Result<TxEffects, StateError> {
+        self.simulate_tx_batch(std::slice::from_ref(tx))
+    }
+
+    /// Simulates applying a whole lot of transactions, running every check that [State::apply_tx_batch] does, and reports their effects without modifying the state.
+    pub fn simulate_tx_batch(&self, txx: &[Transaction]) -> Result<TxEffects, StateError> {
+        simulate_tx_batch_impl(self, txx)
+    }
+
     /// Calculates the "transactions" root hash. Note that this is different depending on whether the block is pre-tip908 or post-tip908.
     pub fn transactions_root_hash(&self) -> HashVal {
         if self.tip_908() {
             HashVal(self.tip908_transactions().root_hash())
         } else {
-            let db = Database::new(InMemoryCas::default());
-            let mut smt = SmtMapping::new(db.get_tree(Default::default()).unwrap());
-            for (k, v) in self.transactions.iter() {
-                smt.insert(*k, v.clone());
-            }
-            smt.root_hash()
+            self.legacy_transactions().root_hash()
         }
     }
 
+    /// Obtains the sparse merkle tree of transactions used before tip-908.
+    fn legacy_transactions(&self) -> SmtMapping<InMemoryCas, TxHash, Transaction> {
+        let db = Database::new(InMemoryCas::default());
+        let mut smt = SmtMapping::new(db.get_tree(Default::default()).unwrap());
+        for (k, v) in self.transactions.iter() {
+            smt.insert(*k, v.clone());
+        }
+        smt
+    }
+
     /// Obtains the dense merkle tree (tip-908)
     pub fn tip908_transactions(&self) -> DenseMerkleTree {
-        let mut vv = Vec::new();
-        for tx in self.transactions.values() {
-            let complex = tx.hash_nosigs().pipe(|nosigs_hash| {
-                let mut v = nosigs_hash.0.to_vec();
-                v.extend_from_slice(&tx.stdcode().hash().0);
-                v
-            });
-            vv.push(complex);
-        }
+        DenseMerkleTree::new(&self.tip908_leaves())
+    }
+
+    /// Obtains the sorted leaves of the dense merkle tree (tip-908)
+    fn tip908_leaves(&self) -> Vec<Vec<u8>> {
+        let mut vv: Vec<_> = self.transactions.values().map(tip908_leaf).collect();
         vv.sort_unstable();
-        DenseMerkleTree::new(&vv)
+        vv
     }
 
     /// Obtains the sorted position of the given transaction within this state.
@@ -186,12 +264,13 @@ impl<C: ContentAddrStore> State<C> {
     /// Finalizes a state into a block. This consumes the state.
     pub fn seal(mut self, action: Option<ProposerAction>) -> SealedState<C> {
         // first apply melmint
-        self = crate::melmint::preseal_melmint(self);
+        let (state, mut report) = crate::melmint::preseal_melmint_with_report(self);
+        self = state;
         assert!(self.pools.val_iter().count() >= 2);
 
         // then apply tip 909
         if self.tip_909() {
-            let divider = self.height.0.saturating_sub(TIP_909_HEIGHT.0) / 1_000_000;
+            let divider = self.height.0.saturating_sub(self.params.tip_909.0) / 1_000_000;
             let reward = (1u128 << 20) >> divider;
             let tip909a_erg_subsidy = reward >> 8;
             // fee subsidy
@@ -206,6 +285,8 @@ impl<C: ContentAddrStore> State<C> {
                 .0
                 .unwrap();
             let (mel, _) = smpool.swap_many(0, fee_subsidy);
+            report.fee_subsidy.rights_in = fee_subsidy;
+            report.fee_subsidy.lefts_out = mel;
             self.pools
                 .insert(PoolKey::new(Denom::Mel, Denom::Sym), smpool);
             self.fee_pool += CoinValue(mel);
@@ -220,7 +301,9 @@ impl<C: ContentAddrStore> State<C> {
                 .get(&PoolKey::new(Denom::Erg, Denom::Sym))
                 .0
                 .unwrap();
-            let _ = espool.swap_many(0, erg_subsidy);
+            let (erg, _) = espool.swap_many(0, erg_subsidy);
+            report.erg_subsidy.rights_in = erg_subsidy;
+            report.erg_subsidy.lefts_out = erg;
             self.pools
                 .insert(PoolKey::new(Denom::Erg, Denom::Sym), espool);
         }
@@ -230,22 +313,17 @@ impl<C: ContentAddrStore> State<C> {
         // apply the proposer action
         if let Some(action) = action {
             // first let's move the fee multiplier
-            let max_movement = if after_tip_901 {
-                ((self.fee_multiplier >> 7) as i64).max(2)
-            } else {
-                (self.fee_multiplier >> 7) as i64
-            };
-            let scaled_movement = max_movement * action.fee_multiplier_delta as i64 / 128;
+            let new_multiplier = next_fee_multiplier(
+                self.fee_multiplier,
+                action.fee_multiplier_delta,
+                after_tip_901,
+            );
             log::debug!(
-                "changing fee multiplier {} by {}",
+                "changing fee multiplier {} to {}",
                 self.fee_multiplier,
-                scaled_movement
+                new_multiplier
             );
-            if scaled_movement >= 0 {
-                self.fee_multiplier += scaled_movement as u128;
-            } else {
-                self.fee_multiplier -= scaled_movement.abs() as u128;
-            }
+            self.fee_multiplier = new_multiplier;
 
             // then it's time to collect the fees dude! we synthesize a coin with 1/65536 of the fee pool and all the tips.
             let base_fees = CoinValue(self.fee_pool.0 >> 16);
@@ -267,25 +345,122 @@ impl<C: ContentAddrStore> State<C> {
                 .insert_coin(pseudocoin_id, pseudocoin_data, self.tip_906());
         }
         // create the finalized state
-        SealedState(self, action)
+        SealedState(self, action, Some(Arc::new(report)))
+    }
+}
+
+/// Turns a copy of a sealed state, whose header is given, into the unfinalized state of the next block.
+pub(crate) fn advance_state<C: ContentAddrStore>(
+    mut new: State<C>,
+    header: Header,
+    resume_from: Option<&MigrationCheckpoint>,
+    checkpoint_interval: u64,
+    on_checkpoint: impl FnMut(&MigrationCheckpoint) -> ControlFlow<()>,
+) -> Result<State<C>, MigrationError> {
+    let was_tip_906 = new.tip_906();
+    // fee variables
+    new.history.insert(new.height, header);
+    new.height += BlockHeight(1);
+    new.stakes.remove_stale((new.height / STAKE_EPOCH).0);
+    new.transactions.clear();
+    // TIP-906 transition
+    if new.tip_906() && !was_tip_906 {
+        log::warn!("DOING TIP-906 TRANSITION NOW!");
+        run_migration(
+            &mut new,
+            &Tip906Migration,
+            resume_from,
+            checkpoint_interval,
+            on_checkpoint,
+        )?;
     }
+    Ok(new)
+}
+
+/// Checks a block at the given height against the size limits in the consensus parameters, if they apply.
+pub(crate) fn check_block_size(
+    params: &ConsensusParams,
+    height: BlockHeight,
+    block: &Block,
+) -> Result<(), StateError> {
+    if height < params.block_size_limit_height {
+        return Ok(());
+    }
+    let weight = block
+        .transactions
+        .iter()
+        .map(|tx| tx.weight(covenant_weight_from_bytes))
+        .fold(0u128, |a, b| a.saturating_add(b));
+    if block.transactions.len() as u64 > params.max_block_txx || weight > params.max_block_weight {
+        return Err(StateError::BlockTooLarge {
+            txx: block.transactions.len(),
+            weight,
+        });
+    }
+    Ok(())
+}
+
+/// Applies the transactions of a block to the unfinalized state of its height, seals it, and checks the result against the block's header.
+pub(crate) fn apply_block_to<C: ContentAddrStore>(
+    mut basis: State<C>,
+    block: &Block,
+) -> Result<SealedState<C>, StateError> {
+    let transactions = block.transactions.iter().cloned().collect::<Vec<_>>();
+    basis.apply_tx_batch(&transactions)?;
+    let basis = basis.seal(block.proposer_action);
+    assert!(basis.inner_ref().pools.val_iter().count() >= 2);
+
+    if basis.header() != block.header {
+        log::warn!(
+            "post-apply header {:#?} doesn't match declared header {:#?} with {} txx",
+            basis.header(),
+            block.header,
+            transactions.len()
+        );
+        block.transactions.iter().for_each(|tx| {
+            log::warn!("{:?}", tx.kind);
+        });
+
+        Err(StateError::WrongHeader)
+    } else {
+        Ok(basis)
+    }
+}
+
+/// Encodes a transaction as a leaf of the tip-908 dense merkle tree.
+pub(crate) fn tip908_leaf(tx: &Transaction) -> Vec<u8> {
+    tx.hash_nosigs().pipe(|nosigs_hash| {
+        let mut v = nosigs_hash.0.to_vec();
+        v.extend_from_slice(&tx.stdcode().hash().0);
+        v
+    })
 }
 
 /// SealedState represents an immutable state at a finalized block height.
 /// It cannot be constructed except through sealing a State or restoring from persistent storage.
 #[derive(Derivative, Debug)]
 #[derivative(Clone(bound = ""))]
-pub struct SealedState<C: ContentAddrStore>(State<C>, Option<ProposerAction>);
+pub struct SealedState<C: ContentAddrStore>(
+    State<C>,
+    Option<ProposerAction>,
+    Option<Arc<MelmintReport>>,
+);
 
 impl<C: ContentAddrStore> SealedState<C> {
-    /// Regenerate from a block, given a database to get the SMTs out of.
+    /// Regenerate from a block, given a database to get the SMTs out of. The default consensus parameters for the block's network are used.
     pub fn from_block(blk: &Block, db: &Database<C>) -> Self {
+        Self::from_block_with_params(blk, db, ConsensusParams::for_network(blk.header.network))
+    }
+
+    /// Regenerate from a block, given a database to get the SMTs out of and the consensus parameters of the network.
+    pub fn from_block_with_params(blk: &Block, db: &Database<C>, params: ConsensusParams) -> Self {
         let coins = CoinMapping::new(db.get_tree(blk.header.coins_hash.0).unwrap());
         let history = SmtMapping::new(db.get_tree(blk.header.history_hash.0).unwrap());
         let stakes = SmtMapping::new(db.get_tree(blk.header.stakes_hash.0).unwrap());
         let pools = SmtMapping::new(db.get_tree(blk.header.pools_hash.0).unwrap());
         let state = State {
             network: blk.header.network,
+            params,
             height: blk.header.height,
             history,
             coins,
@@ -301,12 +476,30 @@ impl<C: ContentAddrStore> SealedState<C> {
             pools,
             stakes,
         };
-        Self(state, blk.proposer_action)
+        Self(state, blk.proposer_action, None)
     }
 
     /// From raw parts
     pub fn from_parts(state: State<C>, prop_action: Option<ProposerAction>) -> Self {
-        Self(state, prop_action)
+        Self(state, prop_action, None)
+    }
+
+    /// Restores the address index of the coin mapping from its root hash. See [CoinMapping::restore_address_index].
+    pub fn with_address_index(mut self, root: HashVal) -> Self {
+        self.0.coins.restore_address_index(root);
+        self
+    }
+
+    /// Restores the melmint report of a state that was sealed elsewhere, as persisted from [SealedState::melmint_report].
+    pub fn with_melmint_report(mut self, report: MelmintReport) -> Self {
+        self.2 = Some(Arc::new(report));
+        self
+    }
+
+    /// Restores the key index of the pool mapping from its root hash. See [SmtMapping::restore_key_index].
+    pub fn with_pool_key_index(mut self, root: HashVal) -> Self {
+        self.0.pools.restore_key_index(root);
+        self
     }
 
     /// Returns a reference to the State finalized within.
@@ -345,6 +538,11 @@ impl<C: ContentAddrStore> SealedState<C> {
         self.1.as_ref()
     }
 
+    /// Returns what melmint did while sealing this state. This is only known if the state was sealed here, or if the report was restored with [SealedState::with_melmint_report].
+    pub fn melmint_report(&self) -> Option<&MelmintReport> {
+        self.2.as_deref()
+    }
+
     /// Returns the final state represented as a "block" (header + transactions).
     pub fn to_block(&self) -> Block {
         Block {
@@ -355,70 +553,62 @@ impl<C: ContentAddrStore> SealedState<C> {
     }
     /// Creates a new unfinalized state representing the next block.
     pub fn next_state(&self) -> State<C> {
-        let mut new = State::clone(self.inner_ref());
-        // fee variables
-        new.history.insert(self.0.height, self.header());
-        new.height += BlockHeight(1);
-        new.stakes.remove_stale((new.height / STAKE_EPOCH).0);
-        new.transactions.clear();
-        // TIP-906 transition
-        if new.tip_906() && !self.inner_ref().tip_906() {
-            log::warn!("DOING TIP-906 TRANSITION NOW!");
-            let old_tree = new.coins.inner().clone();
-            let mut count = old_tree.count();
-            for (_, v) in old_tree.iter() {
-                let cdh: CoinDataHeight =
-                    stdcode::deserialize(&v).expect("pre-tip906 coin tree has non-cdh elements?!");
-                let old_count = new.coins.coin_count(cdh.coin_data.covhash);
-                new.coins
-                    .insert_coin_count(cdh.coin_data.covhash, old_count + 1);
-                if count % 100 == 0 {
-                    log::warn!("{} left", count);
-                }
-                count -= 1;
-            }
-        }
-        new
-        // }
+        self.next_state_resumable(None, 100, |checkpoint| {
+            log::warn!("{} left", checkpoint.total - checkpoint.processed);
+            ControlFlow::Continue(())
+        })
+        .expect("migration failed without a checkpoint")
+    }
+
+    /// Creates a new unfinalized state representing the next block, like [SealedState::next_state], but runs any migration that the next block needs (such as the TIP-906 transition) incrementally.
+    ///
+    /// `on_checkpoint` is called with the progress of the migration, and can interrupt it; the migration can then be resumed by calling this again with the last checkpoint. See [run_migration].
+    pub fn next_state_resumable(
+        &self,
+        resume_from: Option<&MigrationCheckpoint>,
+        checkpoint_interval: u64,
+        on_checkpoint: impl FnMut(&MigrationCheckpoint) -> ControlFlow<()>,
+    ) -> Result<State<C>, MigrationError> {
+        advance_state(
+            State::clone(self.inner_ref()),
+            self.header(),
+            resume_from,
+            checkpoint_interval,
+            on_checkpoint,
+        )
     }
 
     /// Applies a block to this state.
     pub fn apply_block(&self, block: &Block) -> Result<SealedState<C>, StateError> {
-        let mut basis = self.next_state();
+        // check the size limits before doing anything expensive
+        let state = self.inner_ref();
+        check_block_size(&state.params, state.height + BlockHeight(1), block)?;
+        // a state replayed from a witness may lack pools, so this is only checked here
+        let basis = self.next_state();
         assert!(basis.pools.val_iter().count() >= 2);
-        let transactions = block.transactions.iter().cloned().collect::<Vec<_>>();
-        basis.apply_tx_batch(&transactions)?;
-        assert!(basis.pools.val_iter().count() >= 2);
-        let basis = basis.seal(block.proposer_action);
-        assert!(basis.inner_ref().pools.val_iter().count() >= 2);
-
-        if basis.header() != block.header {
-            log::warn!(
-                "post-apply header {:#?} doesn't match declared header {:#?} with {} txx",
-                basis.header(),
-                block.header,
-                transactions.len()
-            );
-            block.transactions.iter().for_each(|tx| {
-                log::warn!("{:?}", tx.kind);
-            });
-
-            Err(StateError::WrongHeader)
-        } else {
-            Ok(basis)
-        }
+        apply_block_to(basis, block)
     }
 
-    /// Confirms a state with a given consensus proof. If called with a second argument, this function is supposed to be called to *verify* the consensus proof.
-    ///
-    /// **TODO**: Right now it DOES NOT check the consensus proof!
-    #[deprecated]
+    /// Confirms a state with a given consensus proof. Superseded by [SealedState::confirm_checked], which reports why a proof is rejected.
+    #[deprecated(note = "use confirm_checked")]
     pub fn confirm(
         self,
         cproof: ConsensusProof,
         _previous_state: Option<&State<C>>,
     ) -> Option<ConfirmedState<C>> {
-        Some(ConfirmedState {
+        self.confirm_checked(cproof).ok()
+    }
+
+    /// Confirms a state with a given consensus proof, checking that it is signed by more than 2/3 of the stake in this block's epoch.
+    pub fn confirm_checked(
+        self,
+        cproof: ConsensusProof,
+    ) -> Result<ConfirmedState<C>, ConsensusProofError> {
+        let header = self.header();
+        self.0
+            .stakes
+            .verify_cproof(header.height.epoch(), header.hash(), &cproof)?;
+        Ok(ConfirmedState {
             state: self,
             cproof,
         })
@@ -459,8 +649,8 @@ mod tests {
 
     use crate::{
         melvm::Covenant,
-        testing::functions::{create_state, valid_txx},
-        StateError,
+        testing::functions::{create_state, faucet_tx, valid_txx},
+        ConsensusParams, SealedState, StateError,
     };
 
     #[test]
@@ -490,6 +680,31 @@ mod tests {
         panic!("should not reach here")
     }
 
+    #[test]
+    fn apply_batch_lenient() {
+        let mut state = create_state(&HashMap::new(), 0);
+        let mut txx = valid_txx(tmelcrypt::ed25519_keygen());
+        // a transaction that spends a coin that doesn't exist
+        let bad_tx = txx[3].clone().tap_mut(|tx| tx.inputs[0].index = 200);
+        txx.insert(5, bad_tx.clone());
+        // a transaction that double-spends an earlier one's input
+        let double_spend = txx[1].clone().tap_mut(|tx| tx.fee += CoinValue(1));
+        txx.push(double_spend.clone());
+
+        let results = state.apply_tx_batch_lenient(&txx);
+        assert_eq!(results.len(), txx.len());
+        for ((txhash, result), tx) in results.iter().zip(txx.iter()) {
+            assert_eq!(*txhash, tx.hash_nosigs());
+            if *txhash == bad_tx.hash_nosigs() || *txhash == double_spend.hash_nosigs() {
+                assert!(result.is_err());
+            } else {
+                assert_eq!(*result, Ok(()));
+                assert!(state.transactions.contains_key(txhash));
+            }
+        }
+        assert_eq!(state.transactions.len(), txx.len() - 2);
+    }
+
     #[test]
     fn fee_pool_increase() {
         let mut state = create_state(&HashMap::new(), 0);
@@ -506,6 +721,7 @@ mod tests {
         let mut state = create_state(&HashMap::new(), 0);
         state.fee_multiplier = 0;
         state.network = NetID::Mainnet;
+        state.params = ConsensusParams::mainnet();
         state
             .apply_tx(&Transaction {
                 kind: TxKind::Faucet,
@@ -519,6 +735,133 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn unbalanced_error_context() {
+        let mut state = create_state(&HashMap::new(), 0);
+        state.fee_multiplier = 0;
+        let start_coin = themelio_structs::CoinID {
+            txhash: tmelcrypt::HashVal([0; 32]).into(),
+            index: 0,
+        };
+        let start_value = state.coins.get_coin(start_coin).unwrap().coin_data.value;
+        let tx = Transaction {
+            kind: TxKind::Normal,
+            inputs: vec![start_coin],
+            outputs: vec![CoinData {
+                denom: Denom::Mel,
+                value: start_value + CoinValue(1),
+                covhash: Covenant::always_true().hash(),
+                additional_data: vec![],
+            }],
+            fee: CoinValue(0),
+            covenants: vec![Covenant::always_true().0],
+            data: vec![],
+            sigs: vec![],
+        };
+        assert_eq!(
+            state.apply_tx(&tx).unwrap_err(),
+            StateError::UnbalancedInOut {
+                txhash: tx.hash_nosigs(),
+                denom: Denom::Mel,
+                input: start_value,
+                output: start_value + CoinValue(1),
+            }
+        );
+
+        // a zero-value output of a denomination that no input has is still unbalanced
+        let tx = Transaction {
+            outputs: vec![
+                CoinData {
+                    denom: Denom::Mel,
+                    value: start_value,
+                    covhash: Covenant::always_true().hash(),
+                    additional_data: vec![],
+                },
+                CoinData {
+                    denom: Denom::Sym,
+                    value: CoinValue(0),
+                    covhash: Covenant::always_true().hash(),
+                    additional_data: vec![],
+                },
+            ],
+            ..tx
+        };
+        assert_eq!(
+            state.apply_tx(&tx).unwrap_err(),
+            StateError::UnbalancedInOut {
+                txhash: tx.hash_nosigs(),
+                denom: Denom::Sym,
+                input: CoinValue(0),
+                output: CoinValue(0),
+            }
+        );
+    }
+
+    #[test]
+    fn custom_tip_heights() {
+        let mut state = create_state(&HashMap::new(), 0);
+        state.params = ConsensusParams {
+            tip_909: 3.into(),
+            allow_faucet: false,
+            ..ConsensusParams::latest()
+        };
+        for _ in 0..3 {
+            assert!(!state.tip_909());
+            assert!(state.tip_908());
+            state = state.seal(None).next_state();
+        }
+        assert!(state.tip_909());
+        assert_eq!(state.params.tip_909, 3.into());
+        assert_eq!(
+            state
+                .apply_tx(&Transaction {
+                    kind: TxKind::Faucet,
+                    inputs: vec![],
+                    outputs: vec![],
+                    data: vec![],
+                    fee: CoinValue(1000),
+                    covenants: vec![],
+                    sigs: vec![],
+                })
+                .unwrap_err(),
+            StateError::MalformedTx
+        );
+    }
+
+    #[test]
+    fn block_size_limits() {
+        // independent transactions, so that the order in which the block applies them doesn't matter
+        let txx: Vec<_> = (0..10u8).map(faucet_tx).collect();
+        let parent = create_state(&HashMap::new(), 0).seal(None);
+        let mut next = parent.next_state();
+        next.apply_tx_batch(&txx).unwrap();
+        let block = next.seal(None).to_block();
+        let fewer = parent
+            .inner_ref()
+            .clone()
+            .tap_mut(|s| s.params.max_block_txx = txx.len() as u64 - 1);
+        assert!(matches!(
+            SealedState::from_parts(fewer.clone(), None).apply_block(&block),
+            Err(StateError::BlockTooLarge { txx: n, .. }) if n == txx.len()
+        ));
+
+        // the limits are only enforced from their activation height
+        let inactive = fewer.tap_mut(|s| s.params.block_size_limit_height = 2.into());
+        assert!(SealedState::from_parts(inactive, None)
+            .apply_block(&block)
+            .is_ok());
+
+        let tighter = parent.inner_ref().clone().tap_mut(|s| {
+            s.params.max_block_txx = txx.len() as u64;
+            s.params.max_block_weight = 1000;
+        });
+        assert!(matches!(
+            SealedState::from_parts(tighter, None).apply_block(&block),
+            Err(StateError::BlockTooLarge { .. })
+        ));
+        assert_eq!(parent.apply_block(&block).map(|_| ()), Ok(()));
+    }
+
     #[test]
     fn staked_coin_cannot_spend() {
         let mut state = create_state(&HashMap::new(), 0);
@@ -617,4 +960,23 @@ mod tests {
             ));
         }
     }
+
+    #[test]
+    fn confirm_checks_cproof() {
+        let stakers: HashMap<_, _> = (0..3)
+            .map(|_| (tmelcrypt::ed25519_keygen().1, CoinValue(100)))
+            .collect();
+        let sealed = create_state(&stakers, 0).seal(None);
+        let header_hash = sealed.header().hash();
+        let cproof = stakers
+            .keys()
+            .map(|sk| (sk.to_public(), sk.sign(&header_hash.0).into()))
+            .collect();
+        let confirmed = sealed.clone().confirm_checked(cproof).unwrap();
+        assert_eq!(confirmed.inner().header(), sealed.header());
+        assert!(sealed.clone().confirm_checked(Default::default()).is_err());
+        #[allow(deprecated)]
+        let legacy = sealed.confirm(Default::default(), None);
+        assert!(legacy.is_none());
+    }
 }
@@ -4,7 +4,7 @@ use novasmt::ContentAddrStore;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rustc_hash::{FxHashMap, FxHashSet};
 use themelio_structs::{
-    Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID, StakeDoc,
+    Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, StakeDoc,
     Transaction, TxHash, TxKind,
 };
 use tmelcrypt::HashVal;
@@ -60,7 +60,11 @@ pub fn apply_tx_batch_impl<C: ContentAddrStore>(
             Covenant(c.to_vec()).weight().unwrap_or(0)
         });
         if tx.fee < min_fee {
-            return Err(StateError::InsufficientFees(min_fee));
+            return Err(StateError::InsufficientFees {
+                txhash,
+                required: min_fee,
+                paid: tx.fee,
+            });
         } else {
             let tips = tx.fee - min_fee;
             next_state.tips.0 = next_state.tips.0.saturating_add(tips.0);
@@ -77,6 +81,44 @@ pub fn apply_tx_batch_impl<C: ContentAddrStore>(
     Ok(next_state)
 }
 
+/// Applies as many transactions from a batch as possible, in order, returning the new state and whether each transaction was accepted.
+///
+/// Chunks of the batch that are valid as a whole are applied with a single call to [apply_tx_batch_impl]; failing chunks are bisected until the offending transactions are isolated.
+pub fn apply_tx_batch_lenient_impl<C: ContentAddrStore>(
+    this: &State<C>,
+    txx: &[Transaction],
+) -> (State<C>, Vec<(TxHash, Result<(), StateError>)>) {
+    fn inner<C: ContentAddrStore>(
+        state: State<C>,
+        txx: &[Transaction],
+        results: &mut Vec<(TxHash, Result<(), StateError>)>,
+    ) -> State<C> {
+        if txx.is_empty() {
+            return state;
+        }
+        match apply_tx_batch_impl(&state, txx) {
+            Ok(next_state) => {
+                results.extend(txx.iter().map(|tx| (tx.hash_nosigs(), Ok(()))));
+                next_state
+            }
+            Err(err) if txx.len() == 1 => {
+                log::debug!("rejecting {}: {:?}", txx[0].hash_nosigs(), err);
+                results.push((txx[0].hash_nosigs(), Err(err)));
+                state
+            }
+            Err(_) => {
+                let (left, right) = txx.split_at(txx.len() / 2);
+                let state = inner(state, left, results);
+                inner(state, right, results)
+            }
+        }
+    }
+
+    let mut results = Vec::with_capacity(txx.len());
+    let next_state = inner(this.clone(), txx, &mut results);
+    (next_state, results)
+}
+
 fn load_relevant_coins<C: ContentAddrStore>(
     this: &State<C>,
     txx: &[Transaction],
@@ -91,11 +133,7 @@ fn load_relevant_coins<C: ContentAddrStore>(
 
         // dedup faucet
         if tx.kind == TxKind::Faucet {
-            // exception to be bug-compatible with the one guy who exploited the inflation bug
-            if this.network == NetID::Mainnet
-                && tx.hash_nosigs().to_string()
-                    != "30a60b20830f000f755b70c57c998553a303cc11f8b1f574d5e9f7e26b645d8b"
-            {
+            if !this.params.faucet_allowed(tx.hash_nosigs()) {
                 return Err(StateError::MalformedTx);
             }
             let pseudocoin = faucet_dedup_pseudocoin(tx.hash_nosigs());
@@ -139,17 +177,22 @@ fn load_relevant_coins<C: ContentAddrStore>(
         .map(|input| (*input, this.coins.get_coin(*input)))
         .collect();
     for tx in txx {
-        for input in tx.inputs.iter() {
+        for (input_index, input) in tx.inputs.iter().enumerate() {
             if !accum.contains_key(input) {
                 // let from_disk = this
                 //     .coins
                 //     .get_coin(*input)
                 //     .ok_or(StateError::NonexistentCoin(*input))?;
-                let from_disk = cache
-                    .get(input)
-                    .unwrap()
-                    .clone()
-                    .ok_or(StateError::NonexistentCoin(*input))?;
+                let from_disk =
+                    cache
+                        .get(input)
+                        .unwrap()
+                        .clone()
+                        .ok_or(StateError::NonexistentCoin {
+                            txhash: tx.hash_nosigs(),
+                            input_index,
+                            coin_id: *input,
+                        })?;
                 accum.insert(*input, from_disk);
             }
         }
@@ -157,9 +200,13 @@ fn load_relevant_coins<C: ContentAddrStore>(
     // ensure no double-spending within this batch
     let mut seen = FxHashSet::default();
     for tx in txx {
-        for input in tx.inputs.iter() {
+        for (input_index, input) in tx.inputs.iter().enumerate() {
             if !seen.insert(input) {
-                return Err(StateError::NonexistentCoin(*input));
+                return Err(StateError::NonexistentCoin {
+                    txhash: tx.hash_nosigs(),
+                    input_index,
+                    coin_id: *input,
+                });
             }
         }
     }
@@ -183,9 +230,7 @@ fn load_stake_info<C: ContentAddrStore>(
             let is_first_coin_not_a_sym: bool = first_coin.denom != Denom::Sym;
 
             // Are we operating under OLD BUGGY RULES?
-            if (this.network == NetID::Mainnet || this.network == NetID::Testnet)
-                && this.height.0 < 500000
-            {
+            if this.height < this.params.legacy_stake_rules_until {
                 log::warn!("LETTING THROUGH BAD STAKING TRANSACTION UNDER OLD BUGGY RULES");
                 continue;
             }
@@ -229,15 +274,20 @@ fn check_tx_validity<C: ContentAddrStore>(
     for (spend_idx, coin_id) in tx.inputs.iter().enumerate() {
         if (new_stakes.contains_key(&coin_id.txhash)
             || this.stakes.get(&coin_id.txhash).0.is_some())
-            && !((this.network == NetID::Mainnet || this.network == NetID::Testnet)
-                && this.height.0 < 900000)
+            && this.height >= this.params.legacy_unlocked_stakes_until
         // Workaround for BUGGY old code!
         {
             return Err(StateError::CoinLocked);
         }
         let coin_data = relevant_coins.get(coin_id);
         match coin_data {
-            None => return Err(StateError::NonexistentCoin(*coin_id)),
+            None => {
+                return Err(StateError::NonexistentCoin {
+                    txhash,
+                    input_index: spend_idx,
+                    coin_id: *coin_id,
+                })
+            }
             Some(coin_data) => {
                 log::trace!(
                     "coin_data {:?} => {:?} for txid {:?}",
@@ -246,23 +296,33 @@ fn check_tx_validity<C: ContentAddrStore>(
                     tx.hash_nosigs()
                 );
                 if !good_scripts.contains(&coin_data.coin_data.covhash) {
+                    let covhash = coin_data.coin_data.covhash;
                     let script = Covenant(
                         scripts
-                            .get(&coin_data.coin_data.covhash)
-                            .ok_or(StateError::NonexistentScript(coin_data.coin_data.covhash))?
+                            .get(&covhash)
+                            .ok_or(StateError::NonexistentScript {
+                                txhash,
+                                input_index: spend_idx,
+                                covhash,
+                            })?
                             .clone(),
                     );
-                    if !script.check(
-                        tx,
-                        CovenantEnv {
-                            parent_coinid: *coin_id,
-                            parent_cdh: coin_data.clone(),
-                            spender_index: spend_idx as u8,
-                            last_header,
-                        },
-                    ) {
-                        return Err(StateError::ViolatesScript(coin_data.coin_data.covhash));
-                    }
+                    script
+                        .check_discerning(
+                            tx,
+                            CovenantEnv {
+                                parent_coinid: *coin_id,
+                                parent_cdh: coin_data.clone(),
+                                spender_index: spend_idx as u8,
+                                last_header,
+                            },
+                        )
+                        .map_err(|reason| StateError::ViolatesScript {
+                            txhash,
+                            input_index: spend_idx,
+                            covhash,
+                            reason,
+                        })?;
                     good_scripts.insert(coin_data.coin_data.covhash);
                 }
                 in_coins.insert(
@@ -284,20 +344,25 @@ fn check_tx_validity<C: ContentAddrStore>(
             {
                 continue;
             }
-            let in_value = if let Some(in_value) = in_coins.get(currency) {
-                *in_value
-            } else {
-                return Err(StateError::UnbalancedInOut);
+            // an output of a denomination absent from the inputs is unbalanced, even if its value is zero
+            let in_value = match in_coins.get(currency) {
+                Some(in_value) => CoinValue(*in_value),
+                None => {
+                    return Err(StateError::UnbalancedInOut {
+                        txhash,
+                        denom: *currency,
+                        input: CoinValue(0),
+                        output: *value,
+                    })
+                }
             };
-            if *value != CoinValue(in_value) {
-                eprintln!(
-                    "unbalanced: {} {:?} in, {} {:?} out",
-                    CoinValue(in_value),
-                    currency,
-                    value,
-                    currency
-                );
-                return Err(StateError::UnbalancedInOut);
+            if *value != in_value {
+                return Err(StateError::UnbalancedInOut {
+                    txhash,
+                    denom: *currency,
+                    input: in_value,
+                    output: *value,
+                });
             }
         }
     }
@@ -314,9 +379,13 @@ fn check_doscmint_validity<C: ContentAddrStore>(
     let coin_id = *tx.inputs.get(0).unwrap();
     let coin_data = relevant_coins
         .get(&coin_id)
-        .ok_or(StateError::NonexistentCoin(coin_id))?;
+        .ok_or(StateError::NonexistentCoin {
+            txhash: tx.hash_nosigs(),
+            input_index: 0,
+            coin_id,
+        })?;
     // make sure the time is long enough that we can easily measure it
-    if (this.height - coin_data.height).0 < 100 && this.network == NetID::Mainnet {
+    if (this.height - coin_data.height).0 < this.params.doscmint_min_age {
         log::warn!("rejecting doscmint due to too recent");
         return Err(StateError::InvalidMelPoW);
     }
@@ -1,20 +1,58 @@
+use std::collections::BTreeMap;
+
 use derivative::Derivative;
-use novasmt::ContentAddrStore;
+use novasmt::{ContentAddrStore, FullProof};
 use stdcode::StdcodeSerializeExt;
-use themelio_structs::{Address, CoinDataHeight, CoinID};
+use themelio_structs::{Address, CoinDataHeight, CoinID, CoinValue, Denom};
 use tmelcrypt::{HashVal, Hashable};
 
+use crate::smtnode::OrderedIter;
+
 /// A mapping that contains the coins, exposing a safeish API for the rest of the crate.
 #[derive(Debug, Derivative)]
 #[derivative(Clone(bound = ""))]
 pub struct CoinMapping<C: ContentAddrStore> {
     inner: novasmt::Tree<C>,
+    index: Option<AddressIndex<C>>,
 }
 
 impl<C: ContentAddrStore> CoinMapping<C> {
     /// Create a new CoinMapping.
     pub fn new(inner: novasmt::Tree<C>) -> Self {
-        Self { inner }
+        Self { inner, index: None }
+    }
+
+    /// Enables the address index, starting from the given coins, which must be all the coins in the mapping. For large states, use [AddressIndexBackfill](crate::AddressIndexBackfill) instead.
+    pub fn enable_address_index(&mut self, existing: impl IntoIterator<Item = CoinID>) {
+        let mut index = AddressIndex {
+            tree: self.inner.database().get_tree([0; 32]).unwrap(),
+        };
+        for id in existing {
+            if let Some(cdh) = self.get_coin(id) {
+                index.insert(id, &cdh);
+            }
+        }
+        self.index = Some(index);
+    }
+
+    /// Restores a previously enabled address index from its root hash.
+    pub fn restore_address_index(&mut self, root: HashVal) {
+        self.index = Some(AddressIndex {
+            tree: self.inner.database().get_tree(root.0).unwrap(),
+        });
+    }
+
+    /// The address index, if enabled.
+    pub fn address_index(&self) -> Option<&AddressIndex<C>> {
+        self.index.as_ref()
+    }
+
+    /// Adds an existing coin to the address index, enabling it if needed.
+    pub(crate) fn index_existing(&mut self, id: CoinID, cdh: &CoinDataHeight) {
+        if self.index.is_none() {
+            self.enable_address_index([]);
+        }
+        self.index.as_mut().unwrap().insert(id, cdh);
     }
 
     /// Inner SMT mapping.
@@ -22,6 +60,11 @@ impl<C: ContentAddrStore> CoinMapping<C> {
         &self.inner
     }
 
+    /// Replaces the inner SMT mapping, keeping the address index.
+    pub(crate) fn set_inner(&mut self, inner: novasmt::Tree<C>) {
+        self.inner = inner;
+    }
+
     /// Root hash.
     pub fn root_hash(&self) -> HashVal {
         HashVal(self.inner.root_hash())
@@ -29,6 +72,9 @@ impl<C: ContentAddrStore> CoinMapping<C> {
 
     /// Inserts a coin into the coin mapping.
     pub fn insert_coin(&mut self, id: CoinID, data: CoinDataHeight, tip_906: bool) {
+        if let Some(index) = self.index.as_mut() {
+            index.insert(id, &data);
+        }
         let id = id.stdcode();
         let preexist = !self.inner.get(tmelcrypt::hash_single(&id).0).is_empty();
         self.inner
@@ -51,15 +97,32 @@ impl<C: ContentAddrStore> CoinMapping<C> {
         }
     }
 
+    /// Gets a coin from the mapping, along with a proof of its inclusion or non-inclusion.
+    pub fn get_coin_with_proof(&self, id: CoinID) -> (Option<CoinDataHeight>, FullProof) {
+        let (bts, proof) = self.inner.get_with_proof(id.stdcode().hash().0);
+        if bts.is_empty() {
+            (None, proof)
+        } else {
+            (Some(stdcode::deserialize(&bts).unwrap()), proof)
+        }
+    }
+
     /// Removes a coin from the coin mapping.
     pub fn remove_coin(&mut self, id: CoinID, tip_906: bool) {
+        if let Some(cdh) = self.index.is_some().then(|| self.get_coin(id)).flatten() {
+            self.index
+                .as_mut()
+                .unwrap()
+                .remove(id, cdh.coin_data.covhash);
+        }
         let id = id.stdcode();
         if tip_906 {
             let existing = self.inner.get(tmelcrypt::hash_single(&id).0);
             if !existing.is_empty() {
                 let data: CoinDataHeight = stdcode::deserialize(&existing).unwrap();
                 let count = self.coin_count(data.coin_data.covhash);
-                self.insert_coin_count(data.coin_data.covhash, count - 1);
+                // the count can only be missing when replaying an incomplete witness
+                self.insert_coin_count(data.coin_data.covhash, count.saturating_sub(1));
             }
         }
         self.inner.insert(tmelcrypt::hash_single(&id).0, b"");
@@ -76,6 +139,17 @@ impl<C: ContentAddrStore> CoinMapping<C> {
         }
     }
 
+    /// Gets the coin count, along with a proof of the count entry in the coin tree.
+    pub fn coin_count_with_proof(&self, covhash: Address) -> (u64, FullProof) {
+        let count_key = tmelcrypt::hash_keyed(b"coin_count", covhash.0);
+        let (v, proof) = self.inner.get_with_proof(count_key.0);
+        if v.is_empty() {
+            (0, proof)
+        } else {
+            (stdcode::deserialize(&v).unwrap(), proof)
+        }
+    }
+
     pub fn insert_coin_count(&mut self, covhash: Address, count: u64) {
         let count_key = tmelcrypt::hash_keyed(b"coin_count", covhash.0);
         if count == 0 {
@@ -85,3 +159,171 @@ impl<C: ContentAddrStore> CoinMapping<C> {
         }
     }
 }
+
+/// An index from addresses to the unspent coins they own, maintained by a [CoinMapping] once enabled. It is not part of the consensus state.
+#[derive(Debug, Derivative)]
+#[derivative(Clone(bound = ""))]
+pub struct AddressIndex<C: ContentAddrStore> {
+    tree: novasmt::Tree<C>,
+}
+
+impl<C: ContentAddrStore> AddressIndex<C> {
+    /// Root hash of the index.
+    pub fn root_hash(&self) -> HashVal {
+        HashVal(self.tree.root_hash())
+    }
+
+    /// Returns all the unspent coins owned by the given address.
+    pub fn coins_of(&self, covhash: Address) -> BTreeMap<CoinID, CoinDataHeight> {
+        self.coins_iter(covhash, None).collect()
+    }
+
+    /// Returns up to `limit` unspent coins owned by the given address, ordered by the hashes of their IDs and starting after the coin `after`, typically the last coin of the previous page.
+    pub fn coins_of_paged(
+        &self,
+        covhash: Address,
+        after: Option<CoinID>,
+        limit: usize,
+    ) -> Vec<(CoinID, CoinDataHeight)> {
+        self.coins_iter(covhash, after).take(limit).collect()
+    }
+
+    /// Returns the number of unspent coins owned by the given address.
+    pub fn count_of(&self, covhash: Address) -> u64 {
+        self.subtree(covhash).count()
+    }
+
+    /// Returns the balances of the given address in every denomination.
+    pub fn balances_of(&self, covhash: Address) -> BTreeMap<Denom, CoinValue> {
+        let mut accum: BTreeMap<Denom, CoinValue> = BTreeMap::new();
+        for (_, cdh) in self.coins_iter(covhash, None) {
+            let balance = accum.entry(cdh.coin_data.denom).or_default();
+            balance.0 = balance.0.saturating_add(cdh.coin_data.value.0);
+        }
+        accum
+    }
+
+    /// Lazily iterates over the coins owned by the given address, starting after the given coin.
+    fn coins_iter(
+        &self,
+        covhash: Address,
+        after: Option<CoinID>,
+    ) -> impl Iterator<Item = (CoinID, CoinDataHeight)> {
+        let after = after.map(|id| id.stdcode().hash().0);
+        OrderedIter::new(&self.subtree(covhash), after)
+            .map(|(_, v)| stdcode::deserialize(&v).expect("corrupt address index"))
+    }
+
+    fn subtree(&self, covhash: Address) -> novasmt::Tree<C> {
+        let root = self.tree.get(Self::address_key(covhash).0);
+        let root = if root.is_empty() {
+            [0; 32]
+        } else {
+            root.as_ref().try_into().expect("corrupt address index")
+        };
+        self.tree.database().get_tree(root).unwrap()
+    }
+
+    fn insert(&mut self, id: CoinID, cdh: &CoinDataHeight) {
+        let covhash = cdh.coin_data.covhash;
+        let mut subtree = self.subtree(covhash);
+        subtree.insert(id.stdcode().hash().0, &(id, cdh).stdcode());
+        self.set_subtree(covhash, subtree);
+    }
+
+    fn remove(&mut self, id: CoinID, covhash: Address) {
+        let mut subtree = self.subtree(covhash);
+        subtree.insert(id.stdcode().hash().0, b"");
+        self.set_subtree(covhash, subtree);
+    }
+
+    fn set_subtree(&mut self, covhash: Address, subtree: novasmt::Tree<C>) {
+        let root = subtree.root_hash();
+        if root == [0; 32] {
+            self.tree.insert(Self::address_key(covhash).0, b"");
+        } else {
+            self.tree.insert(Self::address_key(covhash).0, &root);
+        }
+    }
+
+    fn address_key(covhash: Address) -> HashVal {
+        tmelcrypt::hash_keyed(b"address_index", covhash.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{CoinID, CoinValue, Denom, Transaction};
+
+    use crate::{
+        melvm::Covenant,
+        testing::functions::{create_state, faucet_tx, valid_txx},
+        SealedState,
+    };
+
+    #[test]
+    fn address_index() {
+        let mut state = create_state(&HashMap::new(), 0);
+        state.coins.enable_address_index([CoinID::zero_zero()]);
+        let always_true = Covenant::always_true().hash();
+        let index = state.coins.address_index().unwrap();
+        assert_eq!(index.coins_of(always_true).len(), 1);
+
+        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let owner = Covenant::std_ed25519_pk_legacy(pk).hash();
+        let txx = valid_txx((pk, sk));
+        state.apply_tx_batch(&txx).unwrap();
+        let faucets: Vec<Transaction> = (0..5u8).map(faucet_tx).collect();
+        state.apply_tx_batch(&faucets).unwrap();
+
+        let index = state.coins.address_index().unwrap();
+        let last_output = txx.last().unwrap().output_coinid(0);
+        assert_eq!(
+            index.coins_of(owner).into_keys().collect::<Vec<_>>(),
+            vec![last_output]
+        );
+        assert_eq!(index.count_of(always_true), 5);
+        assert_eq!(
+            index.balances_of(always_true).get(&Denom::Mel),
+            Some(&CoinValue(5000))
+        );
+        let mut paged: Vec<(CoinID, _)> = vec![];
+        loop {
+            let page = index.coins_of_paged(always_true, paged.last().map(|(id, _)| *id), 2);
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 2);
+            paged.extend(page);
+        }
+        assert_eq!(paged.len(), 5);
+        assert_eq!(
+            paged
+                .into_iter()
+                .collect::<std::collections::BTreeMap<_, _>>(),
+            index.coins_of(always_true)
+        );
+
+        // the index can be restored from its root hash
+        let root = index.root_hash();
+        let sealed = state.seal(None);
+        let db = sealed.inner_ref().coins.inner().database();
+        let restored = SealedState::from_block(&sealed.to_block(), &db).with_address_index(root);
+        assert_eq!(
+            restored
+                .inner_ref()
+                .coins
+                .address_index()
+                .unwrap()
+                .coins_of(owner),
+            sealed
+                .inner_ref()
+                .coins
+                .address_index()
+                .unwrap()
+                .coins_of(owner)
+        );
+    }
+}
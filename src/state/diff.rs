@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, HashMap};
+
+use novasmt::{ContentAddrStore, Database};
+use serde::de::DeserializeOwned;
+use stdcode::StdcodeSerializeExt;
+use themelio_structs::{
+    BlockHeight, CoinDataHeight, CoinID, CoinValue, Header, PoolKey, PoolState, StakeDoc, TxHash,
+};
+use tmelcrypt::{HashVal, Hashable};
+
+use crate::{smtnode::RawNode, MappingKey, SealedState, State};
+
+/// A change to a single entry of a mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryDiff<V> {
+    Added(V),
+    Removed(V),
+    Changed { old: V, new: V },
+}
+
+/// The difference between two sealed states, as computed by [SealedState::diff].
+///
+/// The SMTs only store hashed keys, so an entry whose key cannot be recovered is identified by its hashed key instead; see [MappingKey].
+#[derive(Clone, Debug, Default)]
+pub struct StateDiff {
+    pub coins: BTreeMap<MappingKey<CoinID>, EntryDiff<CoinDataHeight>>,
+    pub pools: BTreeMap<MappingKey<PoolKey>, EntryDiff<PoolState>>,
+    pub stakes: BTreeMap<MappingKey<TxHash>, EntryDiff<StakeDoc>>,
+    pub history: BTreeMap<MappingKey<BlockHeight>, EntryDiff<Header>>,
+
+    pub fee_pool: Option<(CoinValue, CoinValue)>,
+    pub fee_multiplier: Option<(u128, u128)>,
+    pub dosc_speed: Option<(u128, u128)>,
+}
+
+impl StateDiff {
+    /// Returns true iff the two states were identical.
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+            && self.pools.is_empty()
+            && self.stakes.is_empty()
+            && self.history.is_empty()
+            && self.fee_pool.is_none()
+            && self.fee_multiplier.is_none()
+            && self.dosc_speed.is_none()
+    }
+}
+
+impl<C: ContentAddrStore> SealedState<C> {
+    /// Computes what was added, removed or changed going from this state to the other state. This only visits the parts of the states' SMTs that differ, so diffing consecutive states is cheap regardless of their size.
+    ///
+    /// Coin IDs are recovered from the transactions and proposer rewards of both states, and the other keys from the key indexes of the new state, if enabled. Other entries are identified by their hashed keys.
+    pub fn diff(&self, other: &Self) -> StateDiff {
+        let (old, new) = (self.inner_ref(), other.inner_ref());
+        let coin_ids: HashMap<[u8; 32], CoinID> = coin_candidates(old)
+            .chain(coin_candidates(new))
+            .map(|id| (id.stdcode().hash().0, id))
+            .collect();
+        StateDiff {
+            coins: diff_trees(old.coins.inner(), new.coins.inner(), |k| {
+                coin_ids
+                    .get(&k)
+                    .map_or(MappingKey::Hashed(HashVal(k)), |id| MappingKey::Known(*id))
+            }),
+            pools: diff_trees(&old.pools.mapping, &new.pools.mapping, |k| {
+                new.pools.resolve_key(k)
+            }),
+            stakes: diff_trees(&old.stakes.mapping, &new.stakes.mapping, |k| {
+                new.stakes.resolve_key(k)
+            }),
+            history: diff_trees(&old.history.mapping, &new.history.mapping, |k| {
+                new.history.resolve_key(k)
+            }),
+
+            fee_pool: scalar_diff(old.fee_pool, new.fee_pool),
+            fee_multiplier: scalar_diff(old.fee_multiplier, new.fee_multiplier),
+            dosc_speed: scalar_diff(old.dosc_speed, new.dosc_speed),
+        }
+    }
+}
+
+fn scalar_diff<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    (old != new).then(|| (old, new))
+}
+
+/// Returns the IDs of the coins that the state's block may have touched: the inputs and outputs of its transactions, and its proposer reward.
+fn coin_candidates<C: ContentAddrStore>(state: &State<C>) -> impl Iterator<Item = CoinID> + '_ {
+    state
+        .transactions
+        .values()
+        .flat_map(|tx| {
+            let outputs = (0..tx.outputs.len()).map(|index| tx.output_coinid(index as u8));
+            tx.inputs.iter().copied().chain(outputs)
+        })
+        .chain(std::iter::once(CoinID::proposer_reward(state.height)))
+}
+
+/// Diffs two SMTs by their raw contents, identifying each entry by `resolve` applied to its hashed key. Entries that cannot be decoded as `V`, such as the coin counts stored in the coin tree, are skipped.
+pub(crate) fn diff_trees<C: ContentAddrStore, K: Ord, V: DeserializeOwned>(
+    old: &novasmt::Tree<C>,
+    new: &novasmt::Tree<C>,
+    resolve: impl Fn([u8; 32]) -> K,
+) -> BTreeMap<K, EntryDiff<V>> {
+    let mut accum = BTreeMap::new();
+    let decode = |v: &[u8]| stdcode::deserialize::<V>(v).ok();
+    for_each_change(old, new, &mut |k, old_v, new_v| {
+        let diff = if old_v.is_empty() {
+            decode(new_v).map(EntryDiff::Added)
+        } else if new_v.is_empty() {
+            decode(old_v).map(EntryDiff::Removed)
+        } else {
+            decode(old_v)
+                .zip(decode(new_v))
+                .map(|(old, new)| EntryDiff::Changed { old, new })
+        };
+        if let Some(diff) = diff {
+            accum.insert(resolve(k), diff);
+        }
+    });
+    accum
+}
+
+/// Returns the previous raw value of every entry that differs between two SMTs, with an empty value for entries that did not exist. Writing these values into `new` gives back `old`.
+pub(crate) fn changed_entries<C: ContentAddrStore>(
+    old: &novasmt::Tree<C>,
+    new: &novasmt::Tree<C>,
+) -> BTreeMap<HashVal, Vec<u8>> {
+    let mut accum = BTreeMap::new();
+    for_each_change(old, new, &mut |k, old_v, _| {
+        accum.insert(HashVal(k), old_v.to_vec());
+    });
+    accum
+}
+
+/// Calls `f` with the hashed key, the old value and the new value of every entry that differs between two SMTs, where an empty value means the entry does not exist.
+///
+/// Both trees are walked together, skipping every pair of subtrees with equal hashes without loading them, so this costs time proportional to the number of changes rather than to the size of the trees.
+fn for_each_change<C: ContentAddrStore>(
+    old: &novasmt::Tree<C>,
+    new: &novasmt::Tree<C>,
+    f: &mut impl FnMut([u8; 32], &[u8], &[u8]),
+) {
+    walk_changes(
+        &old.database(),
+        &new.database(),
+        old.root_hash(),
+        new.root_hash(),
+        f,
+    )
+}
+
+fn walk_changes<C: ContentAddrStore>(
+    old_db: &Database<C>,
+    new_db: &Database<C>,
+    old: [u8; 32],
+    new: [u8; 32],
+    f: &mut impl FnMut([u8; 32], &[u8], &[u8]),
+) {
+    if old == new {
+        return;
+    }
+    let old_raw = old_db.storage().get(&old);
+    let new_raw = new_db.storage().get(&new);
+    if let (
+        Some(RawNode::Hexary {
+            height: old_height,
+            children: old_children,
+            ..
+        }),
+        Some(RawNode::Hexary {
+            height: new_height,
+            children: new_children,
+            ..
+        }),
+    ) = (
+        old_raw.as_deref().and_then(RawNode::decode),
+        new_raw.as_deref().and_then(RawNode::decode),
+    ) {
+        if old_height == new_height {
+            for (old, new) in old_children.iter().zip(new_children.iter()) {
+                walk_changes(old_db, new_db, *old, *new, f);
+            }
+            return;
+        }
+    }
+
+    // the subtrees are shaped differently, such as when one of them is a single entry or empty, so every entry on at least one side changed; compare them entry by entry
+    // (lookups by key assume a full-height root, so the subtrees are only iterated)
+    let old_tree = old_db.get_tree(old).unwrap();
+    let new_tree = new_db.get_tree(new).unwrap();
+    let old_entries: BTreeMap<_, _> = old_tree.iter().collect();
+    let new_entries: BTreeMap<_, _> = new_tree.iter().collect();
+    for (k, new_v) in new_entries.iter() {
+        match old_entries.get(k) {
+            Some(old_v) if old_v[..] == new_v[..] => {}
+            Some(old_v) => f(*k, old_v, new_v),
+            None => f(*k, &[], new_v),
+        }
+    }
+    for (k, old_v) in old_entries.iter() {
+        if !new_entries.contains_key(k) {
+            f(*k, old_v, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use novasmt::{Database, InMemoryCas};
+    use themelio_structs::{BlockHeight, CoinID};
+    use tmelcrypt::HashVal;
+
+    use super::changed_entries;
+    use crate::{
+        testing::functions::{create_state, valid_txx},
+        EntryDiff, MappingKey,
+    };
+
+    #[test]
+    fn diff_after_transactions() {
+        let mut state = create_state(&HashMap::new(), 0);
+        state.history.enable_key_index();
+        let parent = state.seal(None);
+        assert!(parent.diff(&parent).is_empty());
+
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        let mut next = parent.next_state();
+        next.apply_tx_batch(&txx).unwrap();
+        let child = next.seal(None);
+        let diff = parent.diff(&child);
+
+        let start_coin = CoinID {
+            txhash: tmelcrypt::HashVal([0; 32]).into(),
+            index: 0,
+        };
+        assert!(matches!(
+            diff.coins.get(&MappingKey::Known(start_coin)),
+            Some(EntryDiff::Removed(_))
+        ));
+        let last_output = txx.last().unwrap().output_coinid(0);
+        assert_eq!(
+            diff.coins.get(&MappingKey::Known(last_output)),
+            Some(&EntryDiff::Added(
+                child.inner_ref().coins.get_coin(last_output).unwrap()
+            ))
+        );
+        assert_eq!(
+            diff.history.get(&MappingKey::Known(BlockHeight(0))),
+            Some(&EntryDiff::Added(parent.header()))
+        );
+        assert!(diff.stakes.is_empty());
+        assert!(diff.fee_pool.is_some());
+
+        // diffing in the other direction swaps additions and removals
+        let reverse = child.diff(&parent);
+        assert!(matches!(
+            reverse.coins.get(&MappingKey::Known(start_coin)),
+            Some(EntryDiff::Added(_))
+        ));
+        assert_eq!(reverse.coins.len(), diff.coins.len());
+    }
+
+    #[test]
+    fn walk_matches_full_comparison() {
+        let db = Database::new(InMemoryCas::default());
+        let mut old = db.get_tree([0; 32]).unwrap();
+        for i in 0u16..1000 {
+            old.insert(novasmt::hash_data(&i.to_be_bytes()), &i.to_be_bytes());
+        }
+        // change, remove and add a few entries, including down to and up from a single entry in some subtree
+        let mut new = old.clone();
+        for i in (0u16..1000).step_by(97) {
+            new.insert(novasmt::hash_data(&i.to_be_bytes()), &[]);
+        }
+        for i in (5u16..1000).step_by(89) {
+            new.insert(novasmt::hash_data(&i.to_be_bytes()), b"changed");
+        }
+        for i in 1000u16..1020 {
+            new.insert(novasmt::hash_data(&i.to_be_bytes()), &i.to_be_bytes());
+        }
+
+        let mut expected = BTreeMap::new();
+        for i in 0u16..1020 {
+            let key = novasmt::hash_data(&i.to_be_bytes());
+            let (old_v, new_v) = (old.get(key), new.get(key));
+            if old_v != new_v {
+                expected.insert(HashVal(key), old_v.to_vec());
+            }
+        }
+        assert_eq!(changed_entries(&old, &new), expected);
+        assert!(changed_entries(&old, &old).is_empty());
+        assert_eq!(changed_entries(&new, &old).len(), expected.len());
+    }
+}
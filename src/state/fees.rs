@@ -0,0 +1,197 @@
+use novasmt::ContentAddrStore;
+use themelio_structs::{Address, BlockHeight, CoinValue, Transaction};
+
+use crate::{
+    melvm::{covenant_weight_from_bytes, Covenant},
+    State,
+};
+
+/// A breakdown of the minimum fee of a transaction, as computed by [State::fee_breakdown].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeeBreakdown {
+    /// The minimum fee that the transaction must pay, once signed.
+    pub min_fee: CoinValue,
+    /// The weight of the signed transaction, when it pays exactly the minimum fee.
+    pub weight: u128,
+    /// The weight that does not come from covenants: the serialized size of the signed transaction, plus a penalty for every output and minus a discount for every input.
+    pub size_weight: u128,
+    /// The weight and fee of each covenant, in the order they appear in the transaction.
+    pub covenants: Vec<CovenantFee>,
+}
+
+/// The part of a transaction's fee that pays for running a covenant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CovenantFee {
+    pub covhash: Address,
+    pub weight: u128,
+    pub fee: CoinValue,
+}
+
+/// The range that the fee multiplier can be in at some height, as projected by [State::project_fee_multiplier].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeMultiplierRange {
+    pub height: BlockHeight,
+    pub min: u128,
+    pub max: u128,
+}
+
+impl<C: ContentAddrStore> State<C> {
+    /// Estimates the minimum fee that a transaction must pay to be included in this state, once it has been signed.
+    ///
+    /// The fee is part of the transaction's weight, so this finds the smallest fee that covers the weight of the transaction carrying that same fee. `expected_sig_bytes` is the number of bytes that the signatures not yet in `tx.sigs` will add to the serialized transaction; every ed25519 signature adds 65 bytes.
+    pub fn estimate_min_fee(&self, tx: &Transaction, expected_sig_bytes: u128) -> CoinValue {
+        min_fee_fixpoint(tx, self.fee_multiplier, expected_sig_bytes).fee
+    }
+
+    /// Like [State::estimate_min_fee], but also breaks the weight and fee down by covenant.
+    pub fn fee_breakdown(&self, tx: &Transaction, expected_sig_bytes: u128) -> FeeBreakdown {
+        let tx = min_fee_fixpoint(tx, self.fee_multiplier, expected_sig_bytes);
+        let weight = tx
+            .weight(covenant_weight_from_bytes)
+            .saturating_add(expected_sig_bytes);
+        let covenants: Vec<CovenantFee> = tx
+            .covenants
+            .iter()
+            .map(|cov| {
+                let weight = covenant_weight_from_bytes(cov);
+                CovenantFee {
+                    covhash: Covenant(cov.clone()).hash(),
+                    weight,
+                    fee: CoinValue(weight.saturating_mul(self.fee_multiplier) >> 16),
+                }
+            })
+            .collect();
+        let size_weight = weight.saturating_sub(covenants.iter().map(|c| c.weight).sum::<u128>());
+        FeeBreakdown {
+            min_fee: tx.fee,
+            weight,
+            size_weight,
+            covenants,
+        }
+    }
+
+    /// Projects the range of the fee multiplier over the next `blocks` blocks, if every proposer votes as far as possible in the same direction. Unlike consensus, the projection saturates instead of wrapping around.
+    pub fn project_fee_multiplier(&self, blocks: u64) -> Vec<FeeMultiplierRange> {
+        let (mut min, mut max) = (self.fee_multiplier, self.fee_multiplier);
+        let mut height = self.height;
+        let mut accum = Vec::with_capacity(blocks as usize);
+        for _ in 0..blocks {
+            let after_tip_901 = height >= self.params.tip_901;
+            min = match next_fee_multiplier(min, i8::MIN, after_tip_901) {
+                next if next > min => 0,
+                next => next,
+            };
+            max = match next_fee_multiplier(max, i8::MAX, after_tip_901) {
+                next if next < max => u128::MAX,
+                next => next,
+            };
+            height += BlockHeight(1);
+            accum.push(FeeMultiplierRange { height, min, max });
+        }
+        accum
+    }
+}
+
+/// Returns the fee multiplier after a proposer votes to move it by `delta`, out of a maximum of 128, when sealing a block.
+pub(crate) fn next_fee_multiplier(fee_multiplier: u128, delta: i8, after_tip_901: bool) -> u128 {
+    let max_movement = if after_tip_901 {
+        ((fee_multiplier >> 7) as i64).max(2)
+    } else {
+        (fee_multiplier >> 7) as i64
+    };
+    let scaled_movement = max_movement * delta as i64 / 128;
+    // consensus has always used unchecked arithmetic here, which wraps in release builds
+    if scaled_movement >= 0 {
+        fee_multiplier.wrapping_add(scaled_movement as u128)
+    } else {
+        fee_multiplier.wrapping_sub(scaled_movement.unsigned_abs() as u128)
+    }
+}
+
+/// Returns a copy of the transaction paying the smallest fee that covers its own weight, plus the ballast.
+fn min_fee_fixpoint(tx: &Transaction, fee_multiplier: u128, ballast: u128) -> Transaction {
+    let mut tx = tx.clone();
+    tx.fee = CoinValue(0);
+    // the weight only grows with the fee, so this converges after a few steps
+    loop {
+        let required = tx.base_fee(fee_multiplier, ballast, covenant_weight_from_bytes);
+        if required <= tx.fee {
+            return tx;
+        }
+        tx.fee = required;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{BlockHeight, CoinValue, ProposerAction};
+
+    use crate::{
+        melvm::{covenant_weight_from_bytes, Covenant},
+        testing::functions::{create_state, valid_txx},
+    };
+
+    use super::next_fee_multiplier;
+
+    #[test]
+    fn min_fee_covers_signatures() {
+        let mut state = create_state(&HashMap::new(), 0);
+        state.fee_multiplier = 1 << 20;
+        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let mut tx = valid_txx((pk, sk))[0].clone();
+        tx.sigs.clear();
+
+        let breakdown = state.fee_breakdown(&tx, 65);
+        assert_eq!(breakdown.min_fee, state.estimate_min_fee(&tx, 65));
+        assert_eq!(breakdown.covenants.len(), tx.covenants.len());
+        assert_eq!(
+            breakdown.size_weight + breakdown.covenants.iter().map(|c| c.weight).sum::<u128>(),
+            breakdown.weight
+        );
+
+        let signed = |fee: CoinValue| {
+            let mut tx = tx.clone();
+            tx.fee = fee;
+            tx.signed_ed25519(sk)
+        };
+        let exact = signed(breakdown.min_fee);
+        assert_eq!(exact.weight(covenant_weight_from_bytes), breakdown.weight);
+        assert!(exact.base_fee(state.fee_multiplier, 0, covenant_weight_from_bytes) <= exact.fee);
+        let short = signed(breakdown.min_fee - CoinValue(1));
+        assert!(short.base_fee(state.fee_multiplier, 0, covenant_weight_from_bytes) > short.fee);
+    }
+
+    #[test]
+    fn fee_multiplier_projection() {
+        let state = create_state(&HashMap::new(), 0);
+        let projection = state.project_fee_multiplier(10);
+        assert_eq!(projection.len(), 10);
+        for pair in projection.windows(2) {
+            assert!(pair[1].min <= pair[0].min && pair[1].max >= pair[0].max);
+        }
+
+        // sealing with the largest vote reaches the projected maximum
+        let mut sealed = state.clone().seal(Some(ProposerAction {
+            fee_multiplier_delta: i8::MAX,
+            reward_dest: Covenant::always_true().hash(),
+        }));
+        for range in projection.iter() {
+            assert_eq!(range.height, sealed.inner_ref().height + BlockHeight(1));
+            assert_eq!(range.max, sealed.inner_ref().fee_multiplier);
+            sealed = sealed.next_state().seal(Some(ProposerAction {
+                fee_multiplier_delta: i8::MAX,
+                reward_dest: Covenant::always_true().hash(),
+            }));
+        }
+
+        // below 2, a vote down wraps around when sealing, but the projection stops at zero
+        assert_eq!(next_fee_multiplier(1, i8::MIN, true), u128::MAX);
+        let mut low = state;
+        low.fee_multiplier = 3;
+        let projection = low.project_fee_multiplier(5);
+        assert!(projection.iter().all(|range| range.min <= range.max));
+        assert_eq!(projection.last().unwrap().min, 0);
+    }
+}
@@ -1,15 +1,20 @@
 use crate::State;
 
-use std::{cell::RefCell, convert::TryInto};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+};
 
 use melpow::HashFunction;
 use novasmt::ContentAddrStore;
-use num::{integer::Roots, rational::Ratio, BigInt, BigRational};
+use num::{integer::Roots, rational::Ratio, BigInt, BigRational, One, Zero};
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use tap::Pipe;
 use themelio_structs::{
-    BlockHeight, CoinData, CoinDataHeight, CoinValue, Denom, NetID, PoolKey, PoolState,
+    BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, PoolKey, PoolState,
     Transaction, TxKind, MAX_COINVAL, MICRO_CONVERTER,
 };
 
@@ -91,17 +96,53 @@ pub fn calculate_reward(my_speed: u128, dosc_speed: u128, difficulty: u32, tip91
     result.try_into().unwrap_or(u128::MAX)
 }
 
+/// What melmint did while sealing a block, as returned by [SealedState::melmint_report](crate::SealedState::melmint_report).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MelmintReport {
+    /// What the swap, deposit and withdrawal requests put into each pool and were paid out of it.
+    pub pools: BTreeMap<PoolKey, PoolFlows>,
+    /// The coins that the swap, deposit and withdrawal requests were paid out as, by coin ID.
+    pub payouts: BTreeMap<CoinID, CoinData>,
+    /// The swap requests that were refunded because their minimum output was not met, by the ID of the coin left as it was.
+    pub refunds: BTreeSet<CoinID>,
+    /// What pegging swapped into the MEL/SYM pool. The proceeds are thrown away.
+    pub pegging: PoolFlows,
+    /// The TIP-909 fee subsidy, swapped from SYM to MEL in the MEL/SYM pool. The MEL goes to the fee pool.
+    pub fee_subsidy: PoolFlows,
+    /// The TIP-909 erg subsidy, swapped from SYM to ERG in the ERG/SYM pool. The ERG is thrown away.
+    pub erg_subsidy: PoolFlows,
+}
+
+/// Amounts put into and taken out of a pool, in its left and right denominations and its liquidity token.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolFlows {
+    pub lefts_in: u128,
+    pub rights_in: u128,
+    pub liqs_in: u128,
+    pub lefts_out: u128,
+    pub rights_out: u128,
+    pub liqs_out: u128,
+}
+
 /// Presealing function that is called before a state is sealed to apply melmint actions.
 pub fn preseal_melmint<C: ContentAddrStore>(state: State<C>) -> State<C> {
+    preseal_melmint_with_report(state).0
+}
+
+/// Like [preseal_melmint], but also reports what melmint did.
+pub fn preseal_melmint_with_report<C: ContentAddrStore>(
+    state: State<C>,
+) -> (State<C>, MelmintReport) {
+    let mut report = MelmintReport::default();
     let state = create_builtins(state);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_swaps(state);
+    let state = process_swaps(state, &mut report);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_deposits(state);
+    let state = process_deposits(state, &mut report);
     assert!(state.pools.val_iter().count() >= 2);
-    let state = process_withdrawals(state);
+    let state = process_withdrawals(state, &mut report);
     assert!(state.pools.val_iter().count() >= 2);
-    process_pegging(state)
+    (process_pegging(state, &mut report), report)
 }
 
 /// Creates the built-in pools if they don't exist. The built-in pools start out with nonzero liq, so that they can never be completely depleted. This ensures that built-in pools will always exist in the state.
@@ -128,120 +169,476 @@ fn create_builtins<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
     state
 }
 
+/// The tag that ends the data of a swap request with a minimum output, which also versions the encoding.
+const SWAP_MIN_OUT_TAG: &[u8] = b"minout\x01";
+
+/// The data of a swap transaction: the pool to swap against and, after TIP-911, the minimum amount that the swap must pay out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapData {
+    pub pool_key: PoolKey,
+    /// If the swap would pay out less than this, it is refunded instead of being converted.
+    pub min_out: Option<CoinValue>,
+}
+
+impl SwapData {
+    /// Encodes the swap data: the pool key, followed, if there is a minimum output, by the minimum as a 16-byte big-endian integer and a version tag.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = self.pool_key.to_bytes().to_vec();
+        if let Some(min_out) = self.min_out {
+            v.extend_from_slice(&min_out.0.to_be_bytes());
+            v.extend_from_slice(SWAP_MIN_OUT_TAG);
+        }
+        v
+    }
+
+    /// Decodes swap data. The minimum output is only recognized after TIP-911, and only after the exact encoding of a pool key.
+    pub fn from_bytes(data: &[u8], tip_911: bool) -> Option<Self> {
+        let suffix_len = 16 + SWAP_MIN_OUT_TAG.len();
+        if tip_911 && data.len() > suffix_len && data.ends_with(SWAP_MIN_OUT_TAG) {
+            let (prefix, suffix) = data.split_at(data.len() - suffix_len);
+            if let Some(pool_key) = PoolKey::from_bytes(prefix) {
+                if pool_key.to_bytes()[..] == *prefix {
+                    return Some(Self {
+                        pool_key,
+                        min_out: Some(CoinValue(u128::from_be_bytes(
+                            suffix[..16].try_into().unwrap(),
+                        ))),
+                    });
+                }
+            }
+        }
+        Some(Self {
+            pool_key: PoolKey::from_bytes(data)?,
+            min_out: None,
+        })
+    }
+}
+
+/// Returns what a transaction will swap when the state is sealed, if it is a valid swap request.
+pub(crate) fn swap_request<C: ContentAddrStore>(
+    state: &State<C>,
+    tx: &Transaction,
+) -> Option<SwapData> {
+    (!tx.outputs.is_empty()).then(|| ())?; // ensure not empty
+    state.coins.get_coin(tx.output_coinid(0))?; // ensure that first output is unspent
+    let swap = SwapData::from_bytes(&tx.data, state.tip_911())?; // ensure that data contains a pool key
+    let pool_key = swap.pool_key;
+    state.pools.get(&pool_key).0?; // ensure that pool key points to a valid pool
+    (tx.outputs[0].denom == pool_key.left || tx.outputs[0].denom == pool_key.right).then(|| swap)
+    // ensure that the first output is either left or right
+}
+
+/// Returns the pool that a transaction will deposit into when the state is sealed, if it is a valid deposit request.
+pub(crate) fn deposit_request<C: ContentAddrStore>(
+    state: &State<C>,
+    tx: &Transaction,
+) -> Option<PoolKey> {
+    (tx.kind == TxKind::LiqDeposit
+        && tx.outputs.len() >= 2
+        && state.coins.get_coin(tx.output_coinid(0)).is_some()
+        && state.coins.get_coin(tx.output_coinid(1)).is_some())
+    .then(|| ())?;
+    let pool_key = PoolKey::from_bytes(&tx.data)?;
+    (tx.outputs[0].denom == pool_key.left && tx.outputs[1].denom == pool_key.right)
+        .then(|| pool_key)
+}
+
+/// Returns the pool that a transaction will withdraw from when the state is sealed, if it is a valid withdrawal request.
+pub(crate) fn withdraw_request<C: ContentAddrStore>(
+    state: &State<C>,
+    tx: &Transaction,
+) -> Option<PoolKey> {
+    (tx.kind == TxKind::LiqWithdraw
+        && tx.outputs.len() == 1
+        && state.coins.get_coin(tx.output_coinid(0)).is_some())
+    .then(|| ())?;
+    let pool_key = PoolKey::from_bytes(&tx.data)?;
+    state.pools.get(&pool_key).0?;
+    (tx.outputs[0].denom == pool_key.liq_token_denom()).then(|| pool_key)
+}
+
+/// A quote for swapping into a pool, as computed by [State::quote_swap]. Prices are in units of the output denomination per unit of the input denomination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwapQuote {
+    pub denom_out: Denom,
+    /// The exact amount that the swap pays out when the block is sealed.
+    pub amount_out: CoinValue,
+    /// The price that the swap actually gets: the amount paid out divided by the amount put in.
+    pub effective_price: BigRational,
+    /// The price implied by the pool's reserves before the swap.
+    pub spot_price: BigRational,
+    /// How much worse the effective price is than the spot price, as a fraction of the spot price.
+    pub price_impact: BigRational,
+}
+
+impl<C: ContentAddrStore> State<C> {
+    /// Quotes swapping `amount` of `denom_in`, without a minimum output, into the given pool, if it were the only swap against the pool in this block. Returns `None` if the pool does not exist or does not trade `denom_in`, or if the amount is zero.
+    ///
+    /// The quote follows the batch clearing done when sealing, so it is exact as long as no other swaps against the pool are included.
+    pub fn quote_swap(
+        &self,
+        pool: PoolKey,
+        denom_in: Denom,
+        amount: CoinValue,
+    ) -> Option<SwapQuote> {
+        self.quote_swap_among(pool, denom_in, amount, vec![])
+    }
+
+    /// Like [State::quote_swap], but clears the swap together with the swaps against the same pool that are already among this state's transactions, including the refunds of those whose minimum output is not met.
+    pub fn quote_swap_pending(
+        &self,
+        pool: PoolKey,
+        denom_in: Denom,
+        amount: CoinValue,
+    ) -> Option<SwapQuote> {
+        self.quote_swap_among(pool, denom_in, amount, self.pending_swaps(pool))
+    }
+
+    /// Returns the swaps against the given pool that are among this state's transactions.
+    fn pending_swaps(&self, pool: PoolKey) -> Vec<PendingSwap> {
+        self.transactions
+            .values()
+            .filter_map(|tx| {
+                let swap = swap_request(self, tx)?;
+                (swap.pool_key == pool).then(|| PendingSwap {
+                    denom: tx.outputs[0].denom,
+                    value: tx.outputs[0].value.0,
+                    min_out: swap.min_out.map(|v| v.0),
+                })
+            })
+            .collect()
+    }
+
+    fn quote_swap_among(
+        &self,
+        pool: PoolKey,
+        denom_in: Denom,
+        amount: CoinValue,
+        mut swaps: Vec<PendingSwap>,
+    ) -> Option<SwapQuote> {
+        let pool_state = self.pools.get(&pool).0?;
+        let (reserve_in, reserve_out, denom_out) = if denom_in == pool.left {
+            (pool_state.lefts, pool_state.rights, pool.right)
+        } else if denom_in == pool.right {
+            (pool_state.rights, pool_state.lefts, pool.left)
+        } else {
+            return None;
+        };
+        if amount.0 == 0 {
+            return None;
+        }
+        swaps.push(PendingSwap {
+            denom: denom_in,
+            value: amount.0,
+            min_out: None,
+        });
+        let (_, outputs) = clear_swaps(pool, pool_state, &swaps);
+        let amount_out = CoinValue(outputs.last().unwrap().unwrap());
+
+        let effective_price = BigRational::new(BigInt::from(amount_out.0), BigInt::from(amount.0));
+        let (spot_price, price_impact) = if reserve_in == 0 || reserve_out == 0 {
+            (BigRational::zero(), BigRational::zero())
+        } else {
+            let spot_price = BigRational::new(BigInt::from(reserve_out), BigInt::from(reserve_in));
+            let price_impact = BigRational::one() - &effective_price / &spot_price;
+            (spot_price, price_impact)
+        };
+        Some(SwapQuote {
+            denom_out,
+            amount_out,
+            effective_price,
+            spot_price,
+            price_impact,
+        })
+    }
+}
+
+/// A preview of depositing into or withdrawing from a pool, as computed by [State::preview_deposit] and [State::preview_withdraw].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiquidityPreview {
+    /// The left-hand tokens put in by a deposit, or paid out by a withdrawal.
+    pub lefts: CoinValue,
+    /// The right-hand tokens put in by a deposit, or paid out by a withdrawal.
+    pub rights: CoinValue,
+    /// The liquidity tokens paid out by a deposit, or put in by a withdrawal.
+    pub liqs: CoinValue,
+    /// The share of the pool's liquidity tokens that the position is worth: after a deposit, or before a withdrawal.
+    pub share: BigRational,
+}
+
+impl<C: ContentAddrStore> State<C> {
+    /// Previews depositing `left` and `right` into the given pool when the block is sealed, together with the swaps and deposits against the pool that are already among this state's transactions. Returns `None` if either amount is zero.
+    ///
+    /// The preview follows the clearing done when sealing, including the swap of every deposit's lefts that happens before deposits are cleared, so it is exact as long as no other requests against the pool are included.
+    pub fn preview_deposit(
+        &self,
+        pool: PoolKey,
+        left: CoinValue,
+        right: CoinValue,
+    ) -> Option<LiquidityPreview> {
+        if left.0 == 0 || right.0 == 0 {
+            return None;
+        }
+        let (mut swaps, mut deposits, _) = self.pending_liquidity(pool);
+        // like every deposit, this one also counts as a swap of its lefts, since its data is the pool key
+        swaps.push(PendingSwap {
+            denom: pool.left,
+            value: left.0,
+            min_out: None,
+        });
+        let pool_state = self
+            .pools
+            .get(&pool)
+            .0
+            .map(|pool_state| clear_swaps(pool, pool_state, &swaps).0);
+        deposits.push((left.0, right.0));
+        let (pool_state, liqs) = clear_deposits(pool_state, &deposits);
+        let liqs = *liqs.last().unwrap();
+        Some(LiquidityPreview {
+            lefts: left,
+            rights: right,
+            liqs: liqs.into(),
+            share: share_of(liqs, pool_state.liqs),
+        })
+    }
+
+    /// Previews withdrawing `liqs` liquidity tokens from the given pool when the block is sealed, together with the swaps, deposits and withdrawals against the pool that are already among this state's transactions. Returns `None` if the pool does not exist, if the amount is zero, or if the withdrawals would take out more liquidity tokens than the pool has issued.
+    ///
+    /// As with [State::preview_deposit], the preview is exact as long as no other requests against the pool are included.
+    pub fn preview_withdraw(&self, pool: PoolKey, liqs: CoinValue) -> Option<LiquidityPreview> {
+        if liqs.0 == 0 {
+            return None;
+        }
+        let (swaps, deposits, mut withdrawals) = self.pending_liquidity(pool);
+        let mut pool_state = clear_swaps(pool, self.pools.get(&pool).0?, &swaps).0;
+        if !deposits.is_empty() {
+            pool_state = clear_deposits(Some(pool_state), &deposits).0;
+        }
+        withdrawals.push(liqs.0);
+        let total_liqs = withdrawals.iter().fold(0u128, |a, b| a.saturating_add(*b));
+        if total_liqs > pool_state.liqs {
+            return None;
+        }
+        let share = share_of(liqs.0, pool_state.liqs);
+        let (_, payouts) = clear_withdrawals(pool_state, &withdrawals);
+        let (lefts, rights) = *payouts.last().unwrap();
+        Some(LiquidityPreview {
+            lefts: lefts.into(),
+            rights: rights.into(),
+            liqs,
+            share,
+        })
+    }
+
+    /// Returns the swaps, deposits and withdrawals against the given pool that are among this state's transactions. Deposits are among the swaps too.
+    fn pending_liquidity(&self, pool: PoolKey) -> (Vec<PendingSwap>, Vec<(u128, u128)>, Vec<u128>) {
+        let deposits = self
+            .transactions
+            .values()
+            .filter(|tx| deposit_request(self, tx) == Some(pool))
+            .map(|tx| (tx.outputs[0].value.0, tx.outputs[1].value.0))
+            .collect();
+        let withdrawals = self
+            .transactions
+            .values()
+            .filter(|tx| withdraw_request(self, tx) == Some(pool))
+            .map(|tx| tx.outputs[0].value.0)
+            .collect();
+        (self.pending_swaps(pool), deposits, withdrawals)
+    }
+}
+
+/// Returns `part` as a fraction of `whole`, or zero if `whole` is zero.
+fn share_of(part: u128, whole: u128) -> BigRational {
+    if whole == 0 {
+        BigRational::zero()
+    } else {
+        BigRational::new(BigInt::from(part), BigInt::from(whole))
+    }
+}
+
 /// Process swaps.
-fn process_swaps<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_swaps<C: ContentAddrStore>(mut state: State<C>, report: &mut MelmintReport) -> State<C> {
     // find the swap requests
-    let swap_reqs: Vec<Transaction> = state
+    let swap_reqs: Vec<(Transaction, SwapData)> = state
         .transactions
         .values()
-        .cloned()
-        .filter_map(|tx| {
-            (!tx.outputs.is_empty()).then(|| ())?; // ensure not empty
-            state.coins.get_coin(tx.output_coinid(0))?; // ensure that first output is unspent
-            let pool_key = PoolKey::from_bytes(&tx.data)?; // ensure that data contains a pool key
-            state.pools.get(&pool_key).0?; // ensure that pool key points to a valid pool
-            (tx.outputs[0].denom == pool_key.left || tx.outputs[0].denom == pool_key.right)
-                .then(|| ())?; // ensure that the first output is either left or right
-            Some(tx)
-        })
-        .collect::<Vec<Transaction>>();
+        .filter_map(|tx| Some((tx.clone(), swap_request(&state, tx)?)))
+        .collect::<Vec<_>>();
 
     log::trace!("{} swap requests", swap_reqs.len());
     // find the pools mentioned
     let mut pools = swap_reqs
         .iter()
-        .map(|tx| PoolKey::from_bytes(&tx.data).expect("swap_reqs contains invalid poolkey"))
+        .map(|(_, swap)| swap.pool_key)
         .collect::<Vec<PoolKey>>();
     pools.sort_unstable();
     pools.dedup();
     // for each pool
     pools.iter().for_each(|pool| {
-        let mut relevant_swaps: Vec<Transaction> = swap_reqs
+        let (mut relevant_swaps, swaps): (Vec<Transaction>, Vec<PendingSwap>) = swap_reqs
             .iter()
-            .filter(|tx| Some(pool) == PoolKey::from_bytes(&tx.data).as_ref())
-            .cloned()
-            .collect();
+            .filter(|(_, swap)| &swap.pool_key == pool)
+            .map(|(tx, swap)| {
+                (
+                    tx.clone(),
+                    PendingSwap {
+                        denom: tx.outputs[0].denom,
+                        value: tx.outputs[0].value.0,
+                        min_out: swap.min_out.map(|v| v.0),
+                    },
+                )
+            })
+            .unzip();
         log::trace!(
             "{} relevant swaps for pool {:?}",
             relevant_swaps.len(),
             pool
         );
-        let mut pool_state = state.pools.get(pool).0.unwrap();
-        // sum up total lefts and rights
-        let total_lefts = relevant_swaps
-            .iter()
-            .map(|tx| {
-                if tx.outputs[0].denom == pool.left {
-                    tx.outputs[0].value
+        let pool_state = state.pools.get(pool).0.unwrap();
+        let (pool_state, outputs) = clear_swaps(*pool, pool_state, &swaps);
+        let flows = report.pools.entry(*pool).or_default();
+
+        relevant_swaps
+            .iter_mut()
+            .zip(outputs)
+            .for_each(|(swap, output)| {
+                let correct_coinid = swap.output_coinid(0);
+                // refunded swaps keep their first output as it is
+                let output = match output {
+                    Some(output) => output,
+                    None => {
+                        report.refunds.insert(correct_coinid);
+                        return;
+                    }
+                };
+                if swap.outputs[0].denom == pool.left {
+                    flows.lefts_in = flows.lefts_in.saturating_add(swap.outputs[0].value.0);
+                    flows.rights_out = flows.rights_out.saturating_add(output);
+                    swap.outputs[0].denom = pool.right;
                 } else {
-                    CoinValue(0)
+                    flows.rights_in = flows.rights_in.saturating_add(swap.outputs[0].value.0);
+                    flows.lefts_out = flows.lefts_out.saturating_add(output);
+                    swap.outputs[0].denom = pool.left;
                 }
-            })
-            .fold(0u128, |a, b| a.saturating_add(b.0));
-        let total_rights = relevant_swaps
+                swap.outputs[0].value = CoinValue(output);
+                report
+                    .payouts
+                    .insert(correct_coinid, swap.outputs[0].clone());
+                state.coins.insert_coin(
+                    correct_coinid,
+                    CoinDataHeight {
+                        coin_data: swap.outputs[0].clone(),
+                        height: state.height,
+                    },
+                    state.tip_906(),
+                );
+            });
+
+        state.pools.insert(*pool, pool_state);
+    });
+
+    state
+}
+
+/// A swap request against a pool: the denomination and amount put in, and the minimum amount that the swap must pay out.
+struct PendingSwap {
+    denom: Denom,
+    value: u128,
+    min_out: Option<u128>,
+}
+
+/// Clears a batch of swaps against a pool, as done when sealing a block. Returns the new pool state and the amount that each swap pays out in the pool's other denomination, or `None` if it is refunded.
+///
+/// Swaps whose minimum output is not met are refunded, and the remaining swaps are cleared again without them, until every remaining swap gets its minimum.
+fn clear_swaps(
+    pool: PoolKey,
+    pool_state: PoolState,
+    swaps: &[PendingSwap],
+) -> (PoolState, Vec<Option<u128>>) {
+    let mut refunded = vec![false; swaps.len()];
+    loop {
+        let included: Vec<(Denom, u128)> = swaps
             .iter()
-            .map(|tx| {
-                if tx.outputs[0].denom == pool.right {
-                    tx.outputs[0].value
-                } else {
-                    CoinValue(0)
+            .zip(refunded.iter())
+            .filter(|(_, refunded)| !**refunded)
+            .map(|(swap, _)| (swap.denom, swap.value))
+            .collect();
+        if included.is_empty() {
+            return (pool_state, vec![None; swaps.len()]);
+        }
+        let (new_pool_state, outputs) = clear_swap_batch(pool, pool_state, &included);
+        let mut outputs = outputs.into_iter();
+        let outputs: Vec<Option<u128>> = refunded
+            .iter()
+            .map(|refunded| if *refunded { None } else { outputs.next() })
+            .collect();
+        let mut settled = true;
+        for (i, swap) in swaps.iter().enumerate() {
+            if let (Some(output), Some(min_out)) = (outputs[i], swap.min_out) {
+                if output < min_out {
+                    refunded[i] = true;
+                    settled = false;
                 }
-            })
-            .fold(0u128, |a, b| a.saturating_add(b.0));
-        // transmute coins
-        let (left_withdrawn, right_withdrawn) = pool_state.swap_many(total_lefts, total_rights);
-
-        relevant_swaps.iter_mut().for_each(|swap| {
-            let correct_coinid = swap.output_coinid(0);
+            }
+        }
+        if settled {
+            return (new_pool_state, outputs);
+        }
+    }
+}
 
-            if swap.outputs[0].denom == pool.left {
-                swap.outputs[0].denom = pool.right;
-                swap.outputs[0].value = CoinValue(multiply_frac(
+/// Clears a batch of swaps, each given as the denomination and amount put in, against a pool. All the swaps are netted against each other through a single [PoolState::swap_many], and the proceeds on each side are split pro rata. Returns the new pool state and the amount that each swap pays out, in the pool's other denomination.
+fn clear_swap_batch(
+    pool: PoolKey,
+    mut pool_state: PoolState,
+    swaps: &[(Denom, u128)],
+) -> (PoolState, Vec<u128>) {
+    // sum up total lefts and rights
+    let total = |denom: Denom| {
+        swaps
+            .iter()
+            .map(|(d, value)| if *d == denom { *value } else { 0 })
+            .fold(0u128, |a, b| a.saturating_add(b))
+    };
+    let total_lefts = total(pool.left);
+    let total_rights = total(pool.right);
+    // transmute coins
+    let (left_withdrawn, right_withdrawn) = pool_state.swap_many(total_lefts, total_rights);
+    let outputs = swaps
+        .iter()
+        .map(|(denom, value)| {
+            if *denom == pool.left {
+                CoinValue(multiply_frac(
                     right_withdrawn,
-                    Ratio::new(swap.outputs[0].value.0, total_lefts),
+                    Ratio::new(*value, total_lefts),
                 ))
-                .min(MAX_COINVAL);
+                .min(MAX_COINVAL)
+                .0
             } else {
-                swap.outputs[0].denom = pool.left;
-                swap.outputs[0].value = CoinValue(multiply_frac(
+                CoinValue(multiply_frac(
                     left_withdrawn,
-                    Ratio::new(swap.outputs[0].value.0, total_rights),
+                    Ratio::new(*value, total_rights),
                 ))
-                .min(MAX_COINVAL);
+                .min(MAX_COINVAL)
+                .0
             }
-            state.coins.insert_coin(
-                correct_coinid,
-                CoinDataHeight {
-                    coin_data: swap.outputs[0].clone(),
-                    height: state.height,
-                },
-                state.tip_906(),
-            );
-        });
-
-        state.pools.insert(*pool, pool_state);
-    });
-
-    state
+        })
+        .collect();
+    (pool_state, outputs)
 }
 
 /// Process deposits.
-fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_deposits<C: ContentAddrStore>(
+    mut state: State<C>,
+    report: &mut MelmintReport,
+) -> State<C> {
     // find the deposit requests
     let deposit_reqs = state
         .transactions
         .values()
         .cloned()
-        .filter_map(|tx| {
-            (tx.kind == TxKind::LiqDeposit
-                && tx.outputs.len() >= 2
-                && state.coins.get_coin(tx.output_coinid(0)).is_some()
-                && state.coins.get_coin(tx.output_coinid(1)).is_some())
-            .then(|| ())?;
-            let pool_key = PoolKey::from_bytes(&tx.data)?;
-            (tx.outputs[0].denom == pool_key.left && tx.outputs[1].denom == pool_key.right)
-                .then(|| tx)
-        })
+        .filter(|tx| deposit_request(&state, tx).is_some())
         .collect::<Vec<_>>();
     log::trace!("{} deposit reqs", deposit_reqs.len());
     // find the pools mentioned
@@ -261,87 +658,64 @@ fn process_deposits<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
             .filter(|tx| PoolKey::from_bytes(&tx.data) == Some(*pool))
             .cloned()
             .collect();
-        // sum up total lefts and rights
-        let total_lefts: u128 = relevant_txx
+        let deposits: Vec<(u128, u128)> = relevant_txx
             .iter()
-            .map(|tx| tx.outputs[0].value.0)
-            .fold(0u128, |a, b| a.saturating_add(b));
-
-        let total_rights: u128 = relevant_txx
-            .iter()
-            .map(|tx| tx.outputs[1].value.0)
-            .fold(0u128, |a, b| a.saturating_add(b));
-
-        let total_mtsqrt = total_lefts.sqrt().saturating_mul(total_rights.sqrt());
+            .map(|tx| (tx.outputs[0].value.0, tx.outputs[1].value.0))
+            .collect();
         // main logic here
-        let total_liqs = if let Some(mut pool_state) = state.pools.get(pool).0 {
-            let liq = pool_state.deposit(total_lefts, total_rights);
-            state.pools.insert(*pool, pool_state);
-            liq
-        } else {
-            let mut pool_state = PoolState::new_empty();
-            let liq = pool_state.deposit(total_lefts, total_rights);
-            state.pools.insert(*pool, pool_state);
-            liq
-        };
+        let (pool_state, liqs) = clear_deposits(state.pools.get(pool).0, &deposits);
+        state.pools.insert(*pool, pool_state);
+        let flows = report.pools.entry(*pool).or_default();
         // divvy up the liqs
-        relevant_txx.iter_mut().for_each(|deposit| {
-            let original_tx = deposit.clone();
-            let my_mtsqrt = deposit.outputs[0]
-                .value
-                .0
-                .sqrt()
-                .saturating_mul(deposit.outputs[1].value.0.sqrt());
-            deposit.outputs[0].denom = pool.liq_token_denom();
-            deposit.outputs[0].value =
-                multiply_frac(total_liqs, Ratio::new(my_mtsqrt, total_mtsqrt)).into();
-            log::debug!(
-                "added {} total liquidity out of {}!",
-                deposit.outputs[0].value,
-                total_liqs
-            );
-            state.coins.insert_coin(
-                original_tx.output_coinid(0),
-                CoinDataHeight {
-                    coin_data: deposit.outputs[0].clone(),
-                    height: state.height,
-                },
-                state.tip_906(),
-            );
-            if (state.network == NetID::Mainnet || state.network == NetID::Testnet)
-                && state.height.0 < 978392
-            {
-                log::warn!("APPLYING OLD RULES THAT LEAD TO INFLATION BUG!!!!!");
-                state
-                    .coins
-                    .remove_coin(deposit.output_coinid(1), state.tip_906());
-            } else {
-                state
-                    .coins
-                    .remove_coin(original_tx.output_coinid(1), state.tip_906());
-            }
-        });
+        relevant_txx
+            .iter_mut()
+            .zip(liqs)
+            .for_each(|(deposit, liqs)| {
+                let original_tx = deposit.clone();
+                flows.lefts_in = flows.lefts_in.saturating_add(deposit.outputs[0].value.0);
+                flows.rights_in = flows.rights_in.saturating_add(deposit.outputs[1].value.0);
+                flows.liqs_out = flows.liqs_out.saturating_add(liqs);
+                deposit.outputs[0].denom = pool.liq_token_denom();
+                deposit.outputs[0].value = liqs.into();
+                log::debug!("added {} liquidity!", deposit.outputs[0].value);
+                report
+                    .payouts
+                    .insert(original_tx.output_coinid(0), deposit.outputs[0].clone());
+                state.coins.insert_coin(
+                    original_tx.output_coinid(0),
+                    CoinDataHeight {
+                        coin_data: deposit.outputs[0].clone(),
+                        height: state.height,
+                    },
+                    state.tip_906(),
+                );
+                if state.height < state.params.legacy_deposit_rules_until {
+                    log::warn!("APPLYING OLD RULES THAT LEAD TO INFLATION BUG!!!!!");
+                    state
+                        .coins
+                        .remove_coin(deposit.output_coinid(1), state.tip_906());
+                } else {
+                    state
+                        .coins
+                        .remove_coin(original_tx.output_coinid(1), state.tip_906());
+                }
+            });
     });
 
     state
 }
 
 /// Process deposits.
-fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_withdrawals<C: ContentAddrStore>(
+    mut state: State<C>,
+    report: &mut MelmintReport,
+) -> State<C> {
     // find the withdrawal requests
     let withdraw_reqs: Vec<Transaction> = state
         .transactions
         .values()
         .cloned()
-        .filter_map(|tx| {
-            (tx.kind == TxKind::LiqWithdraw
-                && tx.outputs.len() == 1
-                && state.coins.get_coin(tx.output_coinid(0)).is_some())
-            .then(|| ())?;
-            let pool_key = PoolKey::from_bytes(&tx.data)?;
-            state.pools.get(&pool_key).0?;
-            (tx.outputs[0].denom == pool_key.liq_token_denom()).then(|| tx)
-        })
+        .filter(|tx| withdraw_request(&state, tx).is_some())
         .collect::<Vec<_>>();
     // find the pools mentioned
     let pools = withdraw_reqs
@@ -360,55 +734,111 @@ fn process_withdrawals<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
             .filter(|tx| PoolKey::from_bytes(&tx.data) == Some(*pool))
             .cloned()
             .collect();
-        // sum up total liqs
-        let total_liqs = relevant_txx
+        let withdrawals: Vec<u128> = relevant_txx
             .iter()
             .map(|tx| tx.outputs[0].value.0)
-            .fold(0u128, |a, b| a.saturating_add(b));
+            .collect();
         // get the state
-        let mut pool_state = state.pools.get(pool).0.unwrap();
-        let (total_left, total_write) = pool_state.withdraw(total_liqs);
+        let pool_state = state.pools.get(pool).0.unwrap();
+        let (pool_state, payouts) = clear_withdrawals(pool_state, &withdrawals);
         state.pools.insert(*pool, pool_state);
+        let flows = report.pools.entry(*pool).or_default();
         // divvy up the lefts and rights
-        relevant_txx.iter_mut().for_each(|deposit| {
-            let coinid_0 = deposit.output_coinid(0);
-            let coinid_1 = deposit.output_coinid(1);
-
-            let my_liqs = deposit.outputs[0].value.0;
-            deposit.outputs[0].denom = pool.left;
-            deposit.outputs[0].value =
-                multiply_frac(total_left, Ratio::new(my_liqs, total_liqs)).into();
-            let synth = CoinData {
-                denom: pool.right,
-                value: multiply_frac(total_write, Ratio::new(my_liqs, total_liqs)).into(),
-                covhash: deposit.outputs[0].covhash,
-                additional_data: deposit.outputs[0].additional_data.clone(),
-            };
-
-            state.coins.insert_coin(
-                coinid_0,
-                CoinDataHeight {
-                    coin_data: deposit.outputs[0].clone(),
-                    height: state.height,
-                },
-                state.tip_906(),
-            );
-            state.coins.insert_coin(
-                coinid_1,
-                CoinDataHeight {
-                    coin_data: synth,
-                    height: state.height,
-                },
-                state.tip_906(),
-            );
-        });
+        relevant_txx
+            .iter_mut()
+            .zip(payouts)
+            .for_each(|(deposit, (lefts, rights))| {
+                let coinid_0 = deposit.output_coinid(0);
+                let coinid_1 = deposit.output_coinid(1);
+
+                flows.liqs_in = flows.liqs_in.saturating_add(deposit.outputs[0].value.0);
+                flows.lefts_out = flows.lefts_out.saturating_add(lefts);
+                flows.rights_out = flows.rights_out.saturating_add(rights);
+                deposit.outputs[0].denom = pool.left;
+                deposit.outputs[0].value = lefts.into();
+                let synth = CoinData {
+                    denom: pool.right,
+                    value: rights.into(),
+                    covhash: deposit.outputs[0].covhash,
+                    additional_data: deposit.outputs[0].additional_data.clone(),
+                };
+                report.payouts.insert(coinid_0, deposit.outputs[0].clone());
+                report.payouts.insert(coinid_1, synth.clone());
+
+                state.coins.insert_coin(
+                    coinid_0,
+                    CoinDataHeight {
+                        coin_data: deposit.outputs[0].clone(),
+                        height: state.height,
+                    },
+                    state.tip_906(),
+                );
+                state.coins.insert_coin(
+                    coinid_1,
+                    CoinDataHeight {
+                        coin_data: synth,
+                        height: state.height,
+                    },
+                    state.tip_906(),
+                );
+            });
     });
 
     state
 }
 
+/// Clears a batch of deposits, each given as the lefts and rights put in, into a pool, which is created if it does not exist yet. The liquidity tokens minted for the whole batch are split by the product of the square roots of each deposit's lefts and rights. Returns the new pool state and the liquidity tokens that each deposit pays out.
+fn clear_deposits(
+    pool_state: Option<PoolState>,
+    deposits: &[(u128, u128)],
+) -> (PoolState, Vec<u128>) {
+    // sum up total lefts and rights
+    let total_lefts: u128 = deposits
+        .iter()
+        .map(|(lefts, _)| *lefts)
+        .fold(0u128, |a, b| a.saturating_add(b));
+    let total_rights: u128 = deposits
+        .iter()
+        .map(|(_, rights)| *rights)
+        .fold(0u128, |a, b| a.saturating_add(b));
+    let total_mtsqrt = total_lefts.sqrt().saturating_mul(total_rights.sqrt());
+    let mut pool_state = pool_state.unwrap_or_else(PoolState::new_empty);
+    let total_liqs = pool_state.deposit(total_lefts, total_rights);
+    let liqs = deposits
+        .iter()
+        .map(|(lefts, rights)| {
+            let my_mtsqrt = lefts.sqrt().saturating_mul(rights.sqrt());
+            multiply_frac(total_liqs, Ratio::new(my_mtsqrt, total_mtsqrt))
+        })
+        .collect();
+    (pool_state, liqs)
+}
+
+/// Clears a batch of withdrawals, each given as the liquidity tokens put in, from a pool. The lefts and rights withdrawn for the whole batch are split pro rata. Returns the new pool state and the lefts and rights that each withdrawal pays out.
+fn clear_withdrawals(
+    mut pool_state: PoolState,
+    withdrawals: &[u128],
+) -> (PoolState, Vec<(u128, u128)>) {
+    // sum up total liqs
+    let total_liqs = withdrawals.iter().fold(0u128, |a, b| a.saturating_add(*b));
+    let (total_left, total_right) = pool_state.withdraw(total_liqs);
+    let payouts = withdrawals
+        .iter()
+        .map(|my_liqs| {
+            (
+                multiply_frac(total_left, Ratio::new(*my_liqs, total_liqs)),
+                multiply_frac(total_right, Ratio::new(*my_liqs, total_liqs)),
+            )
+        })
+        .collect();
+    (pool_state, payouts)
+}
+
 /// Process pegging.
-fn process_pegging<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
+fn process_pegging<C: ContentAddrStore>(
+    mut state: State<C>,
+    report: &mut MelmintReport,
+) -> State<C> {
     // first calculate the implied sym/Erg exchange rate
     let x_sd = if state.tip_902() {
         state
@@ -462,11 +892,15 @@ fn process_pegging<C: ContentAddrStore>(mut state: State<C>) -> State<C> {
         let delta = (desired_mel - sm_pool.lefts) / throttler;
         // we increase mel liquidity by delta, throwing away the syms generated.
         // this nudges the exchange rate while minimizing long-term inflation
-        let _ = sm_pool.swap_many(delta, 0);
+        let (_, syms) = sm_pool.swap_many(delta, 0);
+        report.pegging.lefts_in = delta;
+        report.pegging.rights_out = syms;
     }
     if desired_sym > sm_pool.rights {
         let delta = (desired_sym - sm_pool.rights) / throttler;
-        let _ = sm_pool.swap_many(0, delta);
+        let (mels, _) = sm_pool.swap_many(0, delta);
+        report.pegging.rights_in = delta;
+        report.pegging.lefts_out = mels;
     }
     state.pools.insert(PoolKey::mel_and(Denom::Sym), sm_pool);
     // return the state now
@@ -483,11 +917,14 @@ fn multiply_frac(x: u128, frac: Ratio<u128>) -> u128 {
 #[cfg(test)]
 mod tests {
 
-    use themelio_structs::CoinID;
+    use tap::Tap;
+    use themelio_structs::{BlockHeight, CoinID, TxHash};
+    use tmelcrypt::HashVal;
 
     use crate::{
         melvm::Covenant,
-        testing::functions::{genesis_mel_coin_id, genesis_state},
+        testing::functions::{create_state, faucet_tx, genesis_mel_coin_id, genesis_state},
+        SealedState,
     };
 
     use super::*;
@@ -497,6 +934,232 @@ mod tests {
         assert_eq!(multiply_frac(1000, Ratio::new(2, 1)), 2000)
     }
 
+    #[test]
+    fn quote_matches_clearing() {
+        let mut state = create_state(&Default::default(), 0).seal(None).next_state();
+        let pool = PoolKey::mel_and(Denom::Sym);
+        let start_coin = genesis_mel_coin_id();
+        let fee = CoinValue(100000);
+        let value = state.coins.get_coin(start_coin).unwrap().coin_data.value - fee;
+        let quote = state.quote_swap(pool, Denom::Mel, value).unwrap();
+        assert_eq!(quote.denom_out, Denom::Sym);
+        assert!(quote.effective_price < quote.spot_price);
+        assert!(quote.price_impact > BigRational::zero());
+        assert!(state.quote_swap(pool, Denom::Erg, value).is_none());
+
+        let swap_tx = Transaction {
+            kind: TxKind::Swap,
+            inputs: vec![start_coin],
+            outputs: vec![CoinData {
+                covhash: Covenant::always_true().hash(),
+                value,
+                denom: Denom::Mel,
+                additional_data: vec![],
+            }],
+            fee,
+            covenants: vec![Covenant::always_true().0],
+            data: pool.to_bytes(),
+            sigs: vec![],
+        };
+        state.apply_tx(&swap_tx).unwrap();
+        // a second swap in the same direction shares the proceeds of a larger, more expensive swap
+        let pending = state.quote_swap_pending(pool, Denom::Mel, value).unwrap();
+        assert!(pending.amount_out < quote.amount_out);
+        assert_eq!(
+            state.quote_swap(pool, Denom::Mel, value),
+            Some(quote.clone())
+        );
+
+        let sealed = state.seal(None);
+        let output = sealed
+            .inner_ref()
+            .coins
+            .get_coin(swap_tx.output_coinid(0))
+            .unwrap();
+        assert_eq!(output.coin_data.denom, Denom::Sym);
+        assert_eq!(output.coin_data.value, quote.amount_out);
+    }
+
+    #[test]
+    fn unmet_minimum_is_refunded() {
+        let mut state = create_state(&Default::default(), 0).seal(None).next_state();
+        let pool = PoolKey::mel_and(Denom::Sym);
+        let greedy = SwapData {
+            pool_key: pool,
+            min_out: Some(CoinValue(u128::MAX)),
+        };
+        assert_eq!(SwapData::from_bytes(&greedy.to_bytes(), true), Some(greedy));
+        assert_ne!(
+            SwapData::from_bytes(&greedy.to_bytes(), false),
+            Some(greedy)
+        );
+        // a plain pool key that happens to end in the tag is not read as carrying a minimum
+        let mut hash = [0xab; 32];
+        hash[32 - SWAP_MIN_OUT_TAG.len()..].copy_from_slice(SWAP_MIN_OUT_TAG);
+        let custom = PoolKey::mel_and(Denom::Custom(TxHash(HashVal(hash))));
+        assert_eq!(
+            SwapData::from_bytes(&custom.to_bytes(), true),
+            Some(SwapData {
+                pool_key: custom,
+                min_out: None,
+            })
+        );
+
+        let start_coin = genesis_mel_coin_id();
+        let fee = CoinValue(100000);
+        let value = state.coins.get_coin(start_coin).unwrap().coin_data.value - fee;
+        let quote = state.quote_swap(pool, Denom::Mel, value).unwrap();
+        let swap_tx = Transaction {
+            kind: TxKind::Swap,
+            inputs: vec![start_coin],
+            outputs: vec![CoinData {
+                covhash: Covenant::always_true().hash(),
+                value,
+                denom: Denom::Mel,
+                additional_data: vec![],
+            }],
+            fee,
+            covenants: vec![Covenant::always_true().0],
+            data: pool.to_bytes(),
+            sigs: vec![],
+        };
+        let greedy_tx = faucet_tx(0).tap_mut(|tx| {
+            tx.fee = fee;
+            tx.data = greedy.to_bytes();
+        });
+        state.apply_tx(&swap_tx).unwrap();
+        let pending = state.quote_swap_pending(pool, Denom::Mel, value);
+        // the greedy swap is left out of the clearing
+        state.apply_tx(&greedy_tx).unwrap();
+        assert_eq!(state.quote_swap_pending(pool, Denom::Mel, value), pending);
+
+        let sealed = state.seal(None);
+        let coins = &sealed.inner_ref().coins;
+        let output = coins.get_coin(swap_tx.output_coinid(0)).unwrap();
+        assert_eq!(output.coin_data.denom, Denom::Sym);
+        assert_eq!(output.coin_data.value, quote.amount_out);
+        let refund = coins.get_coin(greedy_tx.output_coinid(0)).unwrap();
+        assert_eq!(refund.coin_data, greedy_tx.outputs[0]);
+
+        // the report agrees with the coins
+        let report = sealed.melmint_report().unwrap();
+        assert_eq!(
+            report.payouts.get(&swap_tx.output_coinid(0)),
+            Some(&output.coin_data)
+        );
+        assert!(report.refunds.contains(&greedy_tx.output_coinid(0)));
+        let flows = report.pools[&pool];
+        assert_eq!(flows.lefts_in, value.0);
+        assert_eq!(flows.rights_out, quote.amount_out.0);
+        assert!(report.fee_subsidy.rights_in > 0 && report.fee_subsidy.lefts_out > 0);
+        let restored = SealedState::from_parts(sealed.inner_ref().clone(), None);
+        assert!(restored.melmint_report().is_none());
+
+        // the report survives a round trip through storage
+        let persisted = stdcode::serialize(report).unwrap();
+        let restored = restored.with_melmint_report(stdcode::deserialize(&persisted).unwrap());
+        assert_eq!(restored.melmint_report(), Some(report));
+    }
+
+    #[test]
+    fn minimum_ignored_before_tip_911() {
+        let mut state = create_state(&Default::default(), 0).seal(None).next_state();
+        state.params.tip_911 = BlockHeight(u64::MAX);
+        let with_min = SwapData {
+            pool_key: PoolKey::mel_and(Denom::Sym),
+            min_out: Some(CoinValue(0)),
+        };
+        // data with a minimum is not a swap request before TIP-911, so its output is left alone
+        let tx = faucet_tx(0).tap_mut(|tx| tx.data = with_min.to_bytes());
+        state.apply_tx(&tx).unwrap();
+        let sealed = state.seal(None);
+        let output = sealed
+            .inner_ref()
+            .coins
+            .get_coin(tx.output_coinid(0))
+            .unwrap();
+        assert_eq!(output.coin_data, tx.outputs[0]);
+        let report = sealed.melmint_report().unwrap();
+        assert!(!report.payouts.contains_key(&tx.output_coinid(0)));
+        assert!(!report.refunds.contains(&tx.output_coinid(0)));
+    }
+
+    #[test]
+    fn preview_matches_clearing() {
+        let mut state = create_state(&Default::default(), 0).seal(None).next_state();
+        let pool = PoolKey::mel_and(Denom::Sym);
+        let start_coin = genesis_mel_coin_id();
+        let fee = CoinValue(100000);
+        let mels = state.coins.get_coin(start_coin).unwrap().coin_data.value - fee;
+        let syms = CoinValue(1_000_000);
+        let faucet_tx = Transaction {
+            kind: TxKind::Faucet,
+            inputs: vec![],
+            outputs: vec![CoinData {
+                covhash: Covenant::always_true().hash(),
+                value: syms,
+                denom: Denom::Sym,
+                additional_data: vec![],
+            }],
+            fee,
+            covenants: vec![],
+            data: vec![],
+            sigs: vec![],
+        };
+        state.apply_tx(&faucet_tx).unwrap();
+        let (left, right) = if pool.left == Denom::Mel {
+            (mels, syms)
+        } else {
+            (syms, mels)
+        };
+        let preview = state.preview_deposit(pool, left, right).unwrap();
+        assert!(preview.share > BigRational::zero() && preview.share < BigRational::one());
+        assert!(state.preview_deposit(pool, left, CoinValue(0)).is_none());
+
+        let deposit_tx = Transaction {
+            kind: TxKind::LiqDeposit,
+            inputs: vec![start_coin, faucet_tx.output_coinid(0)],
+            outputs: vec![
+                CoinData {
+                    covhash: Covenant::always_true().hash(),
+                    value: left,
+                    denom: pool.left,
+                    additional_data: vec![],
+                },
+                CoinData {
+                    covhash: Covenant::always_true().hash(),
+                    value: right,
+                    denom: pool.right,
+                    additional_data: vec![],
+                },
+            ],
+            fee,
+            covenants: vec![Covenant::always_true().0],
+            data: pool.to_bytes(),
+            sigs: vec![],
+        };
+        state.apply_tx(&deposit_tx).unwrap();
+        // a second deposit shares the liquidity minted for the whole batch
+        assert_ne!(
+            state.preview_deposit(pool, left, right),
+            Some(preview.clone())
+        );
+
+        let next = state.seal(None).next_state();
+        let output = next.coins.get_coin(deposit_tx.output_coinid(0)).unwrap();
+        assert_eq!(output.coin_data.denom, pool.liq_token_denom());
+        assert_eq!(output.coin_data.value, preview.liqs);
+
+        // withdrawing the whole position gives back the same share of the pool
+        let withdrawal = next.preview_withdraw(pool, preview.liqs).unwrap();
+        assert_eq!(withdrawal.share, preview.share);
+        assert!(withdrawal.lefts.0 > 0 && withdrawal.rights.0 > 0);
+        let pool_liqs = next.pools.get(&pool).0.unwrap().liqs;
+        assert!(next
+            .preview_withdraw(pool, CoinValue(pool_liqs + 1))
+            .is_none());
+    }
+
     #[test]
     // test a simple deposit flow
     fn simple_deposit() {
@@ -0,0 +1,294 @@
+use novasmt::{dense::verify_dense, CompressedProof, ContentAddrStore};
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use themelio_structs::{Address, BlockHeight, CoinDataHeight, CoinID, Header, Transaction, TxHash};
+use tmelcrypt::{HashVal, Hashable};
+
+use crate::{state::tip908_leaf, ConsensusParams, SealedState};
+
+/// A proof that a transaction is included in a block. Blocks before TIP-908 commit to their transactions in a sparse merkle tree, while later blocks use a dense merkle tree.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionProof {
+    /// A proof against the pre-TIP-908 sparse merkle tree, keyed by the transaction hash.
+    Legacy(CompressedProof),
+    /// A proof against the TIP-908 dense merkle tree, with the index of the transaction's leaf.
+    Dense { index: usize, proof: Vec<HashVal> },
+}
+
+impl<C: ContentAddrStore> SealedState<C> {
+    /// Returns the coin with the given ID (or its absence), along with a proof against the `coins_hash` of this state's header.
+    pub fn coin_proof(&self, coin_id: CoinID) -> (Option<CoinDataHeight>, CompressedProof) {
+        let (cdh, proof) = self.inner_ref().coins.get_coin_with_proof(coin_id);
+        (cdh, proof.compress())
+    }
+
+    /// Returns the number of coins owned by the given address, along with a proof against the `coins_hash` of this state's header. Returns `None` before TIP-906, when coin counts are not committed to.
+    pub fn coin_count_proof(&self, covhash: Address) -> Option<(u64, CompressedProof)> {
+        let inner = self.inner_ref();
+        if !inner.tip_906() {
+            return None;
+        }
+        let (count, proof) = inner.coins.coin_count_with_proof(covhash);
+        Some((count, proof.compress()))
+    }
+
+    /// Returns a proof that the transaction with the given hash is included in this block, against the `transactions_hash` of this state's header.
+    pub fn transaction_proof(&self, txhash: TxHash) -> Option<TransactionProof> {
+        let inner = self.inner_ref();
+        let tx = inner.transactions.get(&txhash)?;
+        if inner.tip_908() {
+            let leaves = inner.tip908_leaves();
+            let index = leaves.binary_search(&tip908_leaf(tx)).ok()?;
+            let proof = novasmt::dense::DenseMerkleTree::new(&leaves)
+                .proof(index)
+                .into_iter()
+                .map(HashVal)
+                .collect();
+            Some(TransactionProof::Dense { index, proof })
+        } else {
+            let (_, proof) = inner.legacy_transactions().get(&txhash);
+            Some(TransactionProof::Legacy(proof.compress()))
+        }
+    }
+
+    /// Returns the header at the given height (or its absence) in this state's history, along with a proof against the `history_hash` of this state's header. The history contains every header strictly before this state's height.
+    pub fn header_proof(&self, height: BlockHeight) -> (Option<Header>, CompressedProof) {
+        let (header, proof) = self.inner_ref().history.get(&height);
+        (header, proof.compress())
+    }
+}
+
+/// Verifies a proof, obtained from [SealedState::coin_proof], that the coin with the given ID has the given value in the state committed to by the header. Passing `None` checks that the coin does not exist.
+pub fn verify_coin_proof(
+    header: &Header,
+    coin_id: CoinID,
+    cdh: Option<&CoinDataHeight>,
+    proof: &CompressedProof,
+) -> bool {
+    let val = cdh.map(|cdh| cdh.stdcode()).unwrap_or_default();
+    proof.decompress().map_or(false, |proof| {
+        proof.verify(header.coins_hash.0, coin_id.stdcode().hash().0, &val)
+    })
+}
+
+/// Verifies a proof, obtained from [SealedState::coin_count_proof], that the address owns exactly `count` coins in the state committed to by the header. Coin counts are only committed to from TIP-906 on, under the default consensus parameters of the header's network.
+pub fn verify_coin_count_proof(
+    header: &Header,
+    covhash: Address,
+    count: u64,
+    proof: &CompressedProof,
+) -> bool {
+    verify_coin_count_proof_with_params(
+        &ConsensusParams::for_network(header.network),
+        header,
+        covhash,
+        count,
+        proof,
+    )
+}
+
+/// Verifies a coin count proof like [verify_coin_count_proof], but with the given consensus parameters.
+pub fn verify_coin_count_proof_with_params(
+    params: &ConsensusParams,
+    header: &Header,
+    covhash: Address,
+    count: u64,
+    proof: &CompressedProof,
+) -> bool {
+    if header.height < params.tip_906 {
+        return false;
+    }
+    // a count of zero is stored as an absent entry
+    let val = if count == 0 { vec![] } else { count.stdcode() };
+    let key = tmelcrypt::hash_keyed(b"coin_count", covhash.0);
+    proof.decompress().map_or(false, |proof| {
+        proof.verify(header.coins_hash.0, key.0, &val)
+    })
+}
+
+/// Verifies a proof, obtained from [SealedState::transaction_proof], that the transaction is included in the block with the given header. The proof must use the scheme in effect at the header's height under the default consensus parameters of the header's network.
+pub fn verify_transaction_proof(
+    header: &Header,
+    tx: &Transaction,
+    proof: &TransactionProof,
+) -> bool {
+    verify_transaction_proof_with_params(
+        &ConsensusParams::for_network(header.network),
+        header,
+        tx,
+        proof,
+    )
+}
+
+/// Verifies a transaction proof like [verify_transaction_proof], but with the given consensus parameters.
+pub fn verify_transaction_proof_with_params(
+    params: &ConsensusParams,
+    header: &Header,
+    tx: &Transaction,
+    proof: &TransactionProof,
+) -> bool {
+    let tip_908 = header.height >= params.tip_908;
+    match proof {
+        TransactionProof::Legacy(proof) if !tip_908 => {
+            let key = tx.hash_nosigs().stdcode().hash();
+            proof.decompress().map_or(false, |proof| {
+                proof.verify(header.transactions_hash.0, key.0, &tx.stdcode())
+            })
+        }
+        TransactionProof::Dense { index, proof } if tip_908 => {
+            let proof: Vec<_> = proof.iter().map(|h| h.0).collect();
+            verify_dense(
+                &proof,
+                header.transactions_hash.0,
+                *index,
+                novasmt::hash_data(&tip908_leaf(tx)),
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Verifies a proof, obtained from [SealedState::header_proof], that the history committed to by `header` contains `historical` at the given height. Passing `None` checks that there is no header at that height.
+pub fn verify_header_proof(
+    header: &Header,
+    height: BlockHeight,
+    historical: Option<&Header>,
+    proof: &CompressedProof,
+) -> bool {
+    let val = historical.map(|h| h.stdcode()).unwrap_or_default();
+    proof.decompress().map_or(false, |proof| {
+        proof.verify(header.history_hash.0, height.stdcode().hash().0, &val)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{BlockHeight, CoinID, NetID};
+
+    use crate::{
+        melvm::Covenant,
+        testing::functions::{create_state, valid_txx},
+        verify_coin_count_proof, verify_coin_count_proof_with_params, verify_coin_proof,
+        verify_header_proof, verify_transaction_proof, ConsensusParams, SealedState,
+        TransactionProof,
+    };
+
+    #[test]
+    fn coin_proofs() {
+        let sealed = create_state(&HashMap::new(), 0).seal(None);
+        let header = sealed.header();
+        let present = CoinID {
+            txhash: tmelcrypt::HashVal([0; 32]).into(),
+            index: 0,
+        };
+        let (cdh, proof) = sealed.coin_proof(present);
+        let cdh = cdh.unwrap();
+        assert!(verify_coin_proof(&header, present, Some(&cdh), &proof));
+        assert!(!verify_coin_proof(&header, present, None, &proof));
+        let mut wrong_cdh = cdh.clone();
+        wrong_cdh.coin_data.value += 1.into();
+        assert!(!verify_coin_proof(
+            &header,
+            present,
+            Some(&wrong_cdh),
+            &proof
+        ));
+
+        let absent = CoinID {
+            txhash: tmelcrypt::HashVal([1; 32]).into(),
+            index: 0,
+        };
+        let (cdh, proof) = sealed.coin_proof(absent);
+        assert!(cdh.is_none());
+        assert!(verify_coin_proof(&header, absent, None, &proof));
+        assert!(!verify_coin_proof(&header, present, None, &proof));
+    }
+
+    #[test]
+    fn coin_count_proofs() {
+        let mut state = create_state(&HashMap::new(), 0);
+        let (pk, sk) = tmelcrypt::ed25519_keygen();
+        let owner = Covenant::std_ed25519_pk_legacy(pk).hash();
+        state.apply_tx_batch(&valid_txx((pk, sk))).unwrap();
+        let sealed = state.seal(None);
+        let header = sealed.header();
+
+        let (count, proof) = sealed.coin_count_proof(owner).unwrap();
+        assert_eq!(count, 1);
+        assert!(verify_coin_count_proof(&header, owner, 1, &proof));
+        assert!(!verify_coin_count_proof(&header, owner, 0, &proof));
+        assert!(!verify_coin_count_proof(&header, owner, 2, &proof));
+
+        // the starting coin has been spent, so its address owns nothing
+        let spent = Covenant::always_true().hash();
+        let (count, proof) = sealed.coin_count_proof(spent).unwrap();
+        assert_eq!(count, 0);
+        assert!(verify_coin_count_proof(&header, spent, 0, &proof));
+
+        // counts are not committed to before TIP-906
+        let mut params = ConsensusParams::latest();
+        params.tip_906 = BlockHeight(100);
+        let mut state = sealed.inner_ref().clone();
+        state.params = params;
+        let legacy = SealedState::from_parts(state, None);
+        assert!(legacy.coin_count_proof(owner).is_none());
+        assert!(!verify_coin_count_proof_with_params(
+            &params, &header, owner, 1, &proof
+        ));
+    }
+
+    #[test]
+    fn transaction_proofs() {
+        for network in [NetID::Custom02, NetID::Mainnet] {
+            let mut state = create_state(&HashMap::new(), 0);
+            state.network = network;
+            state.params = ConsensusParams::for_network(network);
+            let txx = valid_txx(tmelcrypt::ed25519_keygen());
+            state.apply_tx_batch(&txx).unwrap();
+            let sealed = state.seal(None);
+            let header = sealed.header();
+            for tx in txx.iter() {
+                let proof = sealed.transaction_proof(tx.hash_nosigs()).unwrap();
+                assert_eq!(
+                    matches!(proof, TransactionProof::Dense { .. }),
+                    network != NetID::Mainnet
+                );
+                assert!(verify_transaction_proof(&header, tx, &proof));
+                let other = if tx == &txx[0] { &txx[1] } else { &txx[0] };
+                assert!(!verify_transaction_proof(&header, other, &proof));
+            }
+            assert!(sealed
+                .transaction_proof(tmelcrypt::HashVal([1; 32]).into())
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn header_proofs() {
+        let mut sealed = create_state(&HashMap::new(), 0).seal(None);
+        let mut headers = vec![];
+        for _ in 0..10 {
+            headers.push(sealed.header());
+            sealed = sealed.next_state().seal(None);
+        }
+        let latest = sealed.header();
+        for (height, header) in headers.iter().enumerate() {
+            let height = BlockHeight(height as u64);
+            let (historical, proof) = sealed.header_proof(height);
+            assert_eq!(historical.as_ref(), Some(header));
+            assert!(verify_header_proof(&latest, height, Some(header), &proof));
+            assert!(!verify_header_proof(&latest, height, None, &proof));
+            assert!(!verify_header_proof(
+                &latest,
+                height + BlockHeight(1),
+                Some(header),
+                &proof
+            ));
+        }
+        let (historical, proof) = sealed.header_proof(latest.height);
+        assert!(historical.is_none());
+        assert!(verify_header_proof(&latest, latest.height, None, &proof));
+    }
+}
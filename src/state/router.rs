@@ -0,0 +1,250 @@
+use std::collections::BTreeSet;
+
+use novasmt::ContentAddrStore;
+use num::{BigInt, BigRational, One, ToPrimitive};
+use themelio_structs::{Address, CoinData, CoinID, CoinValue, Denom, PoolKey, Transaction, TxKind};
+
+use crate::{melmint::SwapData, State};
+
+/// The longest route that [State::best_swap_route] considers, in hops.
+const MAX_HOPS: usize = 3;
+
+/// A route of swaps through one or more pools, as found by [State::best_swap_route].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwapRoute {
+    pub hops: Vec<SwapHop>,
+    pub amount_in: CoinValue,
+    /// The amount that the last hop is quoted to pay out.
+    pub amount_out: CoinValue,
+}
+
+/// A single swap in a [SwapRoute].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapHop {
+    pub pool: PoolKey,
+    pub denom_in: Denom,
+    pub denom_out: Denom,
+    pub amount_in: CoinValue,
+    pub amount_out: CoinValue,
+    /// The least that the swap accepts to pay out, below which it is refunded instead. This is only set once TIP-911 is active, since swaps cannot carry a minimum before that.
+    pub min_out: Option<CoinValue>,
+}
+
+impl SwapHop {
+    /// Builds a template of the swap transaction for this hop, spending the given coin and paying out to `covhash`, with the hop's minimum output. The template has no fee, covenants or signatures, which the caller needs to fill in.
+    pub fn template(&self, input: CoinID, covhash: Address) -> Transaction {
+        Transaction {
+            kind: TxKind::Swap,
+            inputs: vec![input],
+            outputs: vec![CoinData {
+                covhash,
+                value: self.amount_in,
+                denom: self.denom_in,
+                additional_data: vec![],
+            }],
+            fee: CoinValue(0),
+            covenants: vec![],
+            data: SwapData {
+                pool_key: self.pool,
+                min_out: self.min_out,
+            }
+            .to_bytes(),
+            sigs: vec![],
+        }
+    }
+}
+
+impl SwapRoute {
+    /// Builds templates of the swap transactions along the route, as with [SwapHop::template]. The first swap spends `input`, and every later swap spends the output of the one before it.
+    ///
+    /// Every swap only pays out once the block containing it is sealed, so each must go in a later block than the one before it. Filling in a template changes its hash, so the templates after it then have to be rebuilt with [SwapHop::template].
+    pub fn templates(&self, input: CoinID, covhash: Address) -> Vec<Transaction> {
+        let mut accum: Vec<Transaction> = Vec::with_capacity(self.hops.len());
+        for hop in self.hops.iter() {
+            let input = accum.last().map_or(input, |prev| prev.output_coinid(0));
+            accum.push(hop.template(input, covhash));
+        }
+        accum
+    }
+}
+
+impl<C: ContentAddrStore> State<C> {
+    /// Finds the route of one to three swaps, through distinct denominations, that turns `amount` of `from` into the most of `to`. Ties go to the shorter route.
+    ///
+    /// Every hop is quoted with [State::quote_swap] against the pools as they are in this state, so the later hops are only estimates: by the time they are included, other swaps may have moved the pools. Once TIP-911 is active, every hop therefore gets a minimum output of its quote less `max_slippage`, a fraction between zero and one, so that a swap whose pool moved too far is refunded rather than converted at a worse price.
+    pub fn best_swap_route(
+        &self,
+        from: Denom,
+        to: Denom,
+        amount: CoinValue,
+        max_slippage: &BigRational,
+    ) -> Option<SwapRoute> {
+        if from == to {
+            return None;
+        }
+        let pools = self.routable_pools(&[from, to]);
+        let mut best: Option<SwapRoute> = None;
+        let mut stack = vec![(vec![], from, amount)];
+        while let Some((hops, denom, held)) = stack.pop() {
+            if denom == to {
+                let route = SwapRoute {
+                    hops,
+                    amount_in: amount,
+                    amount_out: held,
+                };
+                let better = match best.as_ref() {
+                    None => true,
+                    Some(best) => {
+                        (route.amount_out, std::cmp::Reverse(route.hops.len()))
+                            > (best.amount_out, std::cmp::Reverse(best.hops.len()))
+                    }
+                };
+                if better {
+                    best = Some(route);
+                }
+                continue;
+            }
+            if hops.len() >= MAX_HOPS {
+                continue;
+            }
+            for pool in pools.iter() {
+                let denom_out = if pool.left == denom {
+                    pool.right
+                } else if pool.right == denom {
+                    pool.left
+                } else {
+                    continue;
+                };
+                let visited = denom_out == from
+                    || hops.iter().any(|hop: &SwapHop| hop.denom_out == denom_out);
+                if visited {
+                    continue;
+                }
+                let quote = match self.quote_swap(*pool, denom, held) {
+                    Some(quote) if quote.amount_out.0 > 0 => quote,
+                    _ => continue,
+                };
+                let mut hops = hops.clone();
+                hops.push(SwapHop {
+                    pool: *pool,
+                    denom_in: denom,
+                    denom_out,
+                    amount_in: held,
+                    amount_out: quote.amount_out,
+                    min_out: self
+                        .tip_911()
+                        .then(|| with_slippage(quote.amount_out, max_slippage)),
+                });
+                stack.push((hops, denom_out, quote.amount_out));
+            }
+        }
+        best
+    }
+
+    /// Returns the keys of the pools that a route can go through: every pool in the pool mapping's key index, if enabled, as well as the pools between the built-in denominations and the given ones.
+    fn routable_pools(&self, denoms: &[Denom]) -> BTreeSet<PoolKey> {
+        let mut pools: BTreeSet<PoolKey> = self
+            .pools
+            .iter()
+            .filter_map(|(key, _)| key.known())
+            .collect();
+        let mut denoms = denoms.to_vec();
+        denoms.extend_from_slice(&[Denom::Mel, Denom::Sym, Denom::Erg]);
+        for (i, a) in denoms.iter().enumerate() {
+            for b in denoms[i + 1..].iter() {
+                if a != b {
+                    let key = PoolKey::new(*a, *b);
+                    if self.pools.get(&key).0.is_some() {
+                        pools.insert(key);
+                    }
+                }
+            }
+        }
+        pools
+    }
+}
+
+/// Returns the amount less the given fraction of it, rounded down.
+fn with_slippage(amount: CoinValue, slippage: &BigRational) -> CoinValue {
+    let kept = (BigRational::one() - slippage) * BigInt::from(amount.0);
+    CoinValue(
+        kept.floor()
+            .to_integer()
+            .to_u128()
+            .unwrap_or(0)
+            .min(amount.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use num::BigRational;
+    use themelio_structs::{BlockHeight, CoinID, CoinValue, Denom, PoolKey};
+
+    use crate::{melvm::Covenant, testing::functions::create_state, SwapRoute};
+
+    #[test]
+    fn routes_through_pools() {
+        let mut state = create_state(&HashMap::new(), 0);
+        for key in [
+            PoolKey::mel_and(Denom::Sym),
+            PoolKey::new(Denom::Erg, Denom::Sym),
+        ] {
+            state.pools.remember_key(&key);
+        }
+        let mut state = state.seal(None).next_state();
+        let keys: Vec<PoolKey> = state
+            .pools
+            .iter()
+            .filter_map(|(key, _)| key.known())
+            .collect();
+        assert!(keys.contains(&PoolKey::mel_and(Denom::Sym)));
+        assert!(keys.contains(&PoolKey::new(Denom::Erg, Denom::Sym)));
+
+        let amount = CoinValue(1_000_000);
+        let slippage = BigRational::new(1.into(), 100.into());
+        let route = state
+            .best_swap_route(Denom::Mel, Denom::Erg, amount, &slippage)
+            .unwrap();
+        let direct = state
+            .quote_swap(PoolKey::mel_and(Denom::Erg), Denom::Mel, amount)
+            .unwrap();
+        assert!(route.amount_out >= direct.amount_out);
+        assert_eq!(route.hops.first().unwrap().denom_in, Denom::Mel);
+        assert_eq!(route.hops.last().unwrap().denom_out, Denom::Erg);
+        assert_eq!(route.hops.last().unwrap().amount_out, route.amount_out);
+        assert!(state
+            .best_swap_route(Denom::Mel, Denom::Mel, amount, &slippage)
+            .is_none());
+
+        // every hop may pay out at most 1% less than quoted, and has no minimum before TIP-911
+        for hop in route.hops.iter() {
+            let min_out = hop.min_out.unwrap();
+            assert!(min_out <= hop.amount_out);
+            assert!(min_out.0 >= hop.amount_out.0 - hop.amount_out.0 / 100 - 1);
+        }
+        state.params.tip_911 = BlockHeight(u64::MAX);
+        let legacy = state
+            .best_swap_route(Denom::Mel, Denom::Erg, amount, &slippage)
+            .unwrap();
+        assert!(legacy.hops.iter().all(|hop| hop.min_out.is_none()));
+
+        // the templates of a longer route are chained through each swap's output
+        let route = state
+            .best_swap_route(Denom::Erg, Denom::Sym, route.amount_out, &slippage)
+            .map(|rest| SwapRoute {
+                hops: route.hops.iter().chain(rest.hops.iter()).copied().collect(),
+                amount_in: amount,
+                amount_out: rest.amount_out,
+            })
+            .unwrap();
+        let templates = route.templates(CoinID::zero_zero(), Covenant::always_true().hash());
+        assert_eq!(templates.len(), route.hops.len());
+        assert_eq!(templates[0].inputs, vec![CoinID::zero_zero()]);
+        for pair in templates.windows(2) {
+            assert_eq!(pair[1].inputs, vec![pair[0].output_coinid(0)]);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use novasmt::ContentAddrStore;
+use themelio_structs::{CoinDataHeight, CoinID, CoinValue, PoolKey, StakeDoc, Transaction, TxHash};
+
+use crate::{
+    melmint::{deposit_request, swap_request, withdraw_request},
+    state::applytx::apply_tx_batch_impl,
+    State, StateError,
+};
+
+/// The effects that applying a batch of transactions would have on a state, as computed by [State::simulate_tx_batch].
+#[derive(Clone, Debug)]
+pub struct TxEffects {
+    /// Coins that would be created and still be unspent after the batch.
+    pub created: BTreeMap<CoinID, CoinDataHeight>,
+    /// Coins that would be spent by the batch, including coins created and spent within the same batch.
+    pub destroyed: BTreeMap<CoinID, CoinDataHeight>,
+    /// Base fees that would go to the fee pool.
+    pub base_fees: CoinValue,
+    /// Fees paid in excess of the base fee, which go to the block proposer.
+    pub tips: CoinValue,
+    /// Stakes that would be created.
+    pub new_stakes: BTreeMap<TxHash, StakeDoc>,
+    /// The DoscMint speed after the batch.
+    pub dosc_speed: u128,
+    /// Melmint requests that would be processed when the state is sealed.
+    pub melmint_requests: Vec<MelmintRequest>,
+}
+
+/// A melmint action that a transaction requests, processed when the state is sealed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MelmintRequest {
+    Swap { txhash: TxHash, pool: PoolKey },
+    Deposit { txhash: TxHash, pool: PoolKey },
+    Withdraw { txhash: TxHash, pool: PoolKey },
+}
+
+/// Runs every validity check for a batch of transactions against the state, and reports what applying them would do without modifying the state.
+pub fn simulate_tx_batch_impl<C: ContentAddrStore>(
+    this: &State<C>,
+    txx: &[Transaction],
+) -> Result<TxEffects, StateError> {
+    let next_state = apply_tx_batch_impl(this, txx)?;
+
+    // coins created by an earlier transaction in the batch can be spent by a later one
+    let mut batch_outputs = BTreeMap::new();
+    for tx in txx {
+        for (i, _) in tx.outputs.iter().enumerate() {
+            let coinid = CoinID::new(tx.hash_nosigs(), i as u8);
+            if let Some(cdh) = next_state.coins.get_coin(coinid) {
+                batch_outputs.insert(coinid, cdh);
+            }
+        }
+    }
+    let mut destroyed = BTreeMap::new();
+    for tx in txx {
+        for (input_index, coinid) in tx.inputs.iter().enumerate() {
+            let cdh = this
+                .coins
+                .get_coin(*coinid)
+                .or_else(|| {
+                    txx.iter()
+                        .find(|prev| prev.hash_nosigs() == coinid.txhash)
+                        .and_then(|prev| prev.outputs.get(coinid.index as usize).cloned())
+                        .map(|coin_data| CoinDataHeight {
+                            coin_data,
+                            height: this.height,
+                        })
+                })
+                .ok_or(StateError::NonexistentCoin {
+                    txhash: tx.hash_nosigs(),
+                    input_index,
+                    coin_id: *coinid,
+                })?;
+            destroyed.insert(*coinid, cdh);
+        }
+    }
+    let created = batch_outputs
+        .into_iter()
+        .filter(|(coinid, _)| !destroyed.contains_key(coinid))
+        .collect();
+
+    let new_stakes = txx
+        .iter()
+        .map(|tx| tx.hash_nosigs())
+        .filter(|txhash| this.stakes.get(txhash).0.is_none())
+        .filter_map(|txhash| Some((txhash, next_state.stakes.get(&txhash).0?)))
+        .collect();
+
+    let mut melmint_requests = Vec::new();
+    for tx in txx {
+        let txhash = tx.hash_nosigs();
+        if let Some(swap) = swap_request(&next_state, tx) {
+            melmint_requests.push(MelmintRequest::Swap {
+                txhash,
+                pool: swap.pool_key,
+            });
+        }
+        if let Some(pool) = deposit_request(&next_state, tx) {
+            melmint_requests.push(MelmintRequest::Deposit { txhash, pool });
+        }
+        if let Some(pool) = withdraw_request(&next_state, tx) {
+            melmint_requests.push(MelmintRequest::Withdraw { txhash, pool });
+        }
+    }
+
+    Ok(TxEffects {
+        created,
+        destroyed,
+        base_fees: next_state.fee_pool - this.fee_pool,
+        tips: next_state.tips - this.tips,
+        new_stakes,
+        dosc_speed: next_state.dosc_speed,
+        melmint_requests,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{CoinData, CoinValue, Denom, PoolKey, Transaction, TxKind};
+
+    use crate::{melvm::Covenant, testing::functions::create_state, MelmintRequest, StateError};
+
+    #[test]
+    fn simulate_swap() {
+        let state = create_state(&HashMap::new(), 0).seal(None).next_state();
+        let start_coin = themelio_structs::CoinID {
+            txhash: tmelcrypt::HashVal([0; 32]).into(),
+            index: 0,
+        };
+        let start_cdh = state.coins.get_coin(start_coin).unwrap();
+        let fee = CoinValue(100000);
+        let swap_tx = Transaction {
+            kind: TxKind::Swap,
+            inputs: vec![start_coin],
+            outputs: vec![CoinData {
+                covhash: Covenant::always_true().hash(),
+                value: start_cdh.coin_data.value - fee,
+                denom: Denom::Mel,
+                additional_data: vec![],
+            }],
+            fee,
+            covenants: vec![Covenant::always_true().0],
+            data: PoolKey::mel_and(Denom::Sym).to_bytes(),
+            sigs: vec![],
+        };
+        let old_root = state.coins.root_hash();
+        let effects = state.simulate_tx(&swap_tx).unwrap();
+        assert_eq!(state.coins.root_hash(), old_root);
+        assert!(state.transactions.is_empty());
+
+        assert_eq!(effects.destroyed.get(&start_coin), Some(&start_cdh));
+        assert_eq!(
+            effects
+                .created
+                .get(&swap_tx.output_coinid(0))
+                .unwrap()
+                .coin_data,
+            swap_tx.outputs[0]
+        );
+        assert_eq!(effects.base_fees + effects.tips, fee);
+        assert!(effects.new_stakes.is_empty());
+        assert_eq!(
+            effects.melmint_requests,
+            vec![MelmintRequest::Swap {
+                txhash: swap_tx.hash_nosigs(),
+                pool: PoolKey::mel_and(Denom::Sym)
+            }]
+        );
+    }
+
+    #[test]
+    fn simulate_rejects_invalid() {
+        let state = create_state(&HashMap::new(), 0);
+        let missing = themelio_structs::CoinID {
+            txhash: tmelcrypt::HashVal([1; 32]).into(),
+            index: 0,
+        };
+        let tx = Transaction {
+            kind: TxKind::Normal,
+            inputs: vec![missing],
+            outputs: vec![],
+            fee: CoinValue(0),
+            covenants: vec![],
+            data: vec![],
+            sigs: vec![],
+        };
+        assert_eq!(
+            state.simulate_tx(&tx).unwrap_err(),
+            StateError::NonexistentCoin {
+                txhash: tx.hash_nosigs(),
+                input_index: 0,
+                coin_id: missing
+            }
+        );
+    }
+}
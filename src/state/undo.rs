@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use novasmt::ContentAddrStore;
+use serde::{Deserialize, Serialize};
+use themelio_structs::{
+    Block, BlockHeight, CoinDataHeight, CoinID, CoinValue, ProposerAction, Transaction,
+};
+use tmelcrypt::HashVal;
+
+use crate::{state::diff::changed_entries, SealedState, StateError};
+
+/// Everything needed to turn the state after a block back into the state before it. It only contains what the block changed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Height of the block that this undoes.
+    pub height: BlockHeight,
+    /// Coins that the block spent, with their previous value.
+    pub spent: BTreeMap<CoinID, CoinDataHeight>,
+    /// Coins that the block created, including the proposer reward.
+    pub created: BTreeSet<CoinID>,
+    /// Previous raw values of the changed pool entries, by hashed key, with empty values for new entries.
+    pub pools: BTreeMap<HashVal, Vec<u8>>,
+    /// Previous raw values of the changed stake entries, likewise.
+    pub stakes: BTreeMap<HashVal, Vec<u8>>,
+
+    pub fee_pool: CoinValue,
+    pub fee_multiplier: u128,
+    pub tips: CoinValue,
+    pub dosc_speed: u128,
+
+    /// The transactions of the parent block, which its header commits to.
+    pub transactions: Vec<Transaction>,
+    /// The proposer action of the parent block.
+    pub proposer_action: Option<ProposerAction>,
+}
+
+impl<C: ContentAddrStore> SealedState<C> {
+    /// Applies a block to this state, also returning a record with which [SealedState::revert] gets back to this state.
+    pub fn apply_block_with_undo(
+        &self,
+        block: &Block,
+    ) -> Result<(SealedState<C>, BlockUndo), StateError> {
+        let child = self.apply_block(block)?;
+        let undo = self.undo_record(&child);
+        Ok((child, undo))
+    }
+
+    /// Reverts the block that produced this state, given its undo record. Returns `None` if the record does not belong to this state.
+    ///
+    /// The block that activates TIP-906 cannot be reverted, since the record lacks the coin counts it backfills.
+    pub fn revert(&self, undo: &BlockUndo) -> Option<SealedState<C>> {
+        let child = self.inner_ref();
+        if child.height != undo.height || child.height.0 == 0 {
+            return None;
+        }
+        let parent_height = BlockHeight(child.height.0 - 1);
+        let parent_header = child.history.get(&parent_height).0?;
+
+        let mut state = child.clone();
+        state.height = parent_height;
+        state.history.delete(&parent_height);
+        for coin_id in undo.created.iter() {
+            state.coins.remove_coin(*coin_id, child.tip_906());
+        }
+        for (coin_id, cdh) in undo.spent.iter() {
+            state
+                .coins
+                .insert_coin(*coin_id, cdh.clone(), child.tip_906());
+        }
+        for (k, v) in undo.pools.iter() {
+            state.pools.mapping.insert(k.0, v);
+        }
+        for (k, v) in undo.stakes.iter() {
+            state.stakes.mapping.insert(k.0, v);
+        }
+        state.fee_pool = undo.fee_pool;
+        state.fee_multiplier = undo.fee_multiplier;
+        state.tips = undo.tips;
+        state.dosc_speed = undo.dosc_speed;
+        state.transactions = undo
+            .transactions
+            .iter()
+            .map(|tx| (tx.hash_nosigs(), tx.clone()))
+            .collect();
+
+        let parent = SealedState::from_parts(state, undo.proposer_action);
+        (parent.header() == parent_header).then(|| parent)
+    }
+
+    fn undo_record(&self, child: &SealedState<C>) -> BlockUndo {
+        let (parent, child) = (self.inner_ref(), child.inner_ref());
+        let mut spent = BTreeMap::new();
+        let mut created = BTreeSet::new();
+        for tx in child.transactions.values() {
+            for coin_id in tx.inputs.iter() {
+                if let Some(cdh) = parent.coins.get_coin(*coin_id) {
+                    if child.coins.get_coin(*coin_id).as_ref() != Some(&cdh) {
+                        spent.insert(*coin_id, cdh);
+                    }
+                }
+            }
+            for index in 0..tx.outputs.len() {
+                created.insert(tx.output_coinid(index as u8));
+            }
+        }
+        created.insert(CoinID::proposer_reward(child.height));
+        created.retain(|coin_id| {
+            parent.coins.get_coin(*coin_id).is_none() && child.coins.get_coin(*coin_id).is_some()
+        });
+
+        BlockUndo {
+            height: child.height,
+            spent,
+            created,
+            pools: changed_entries(&parent.pools.mapping, &child.pools.mapping),
+            stakes: changed_entries(&parent.stakes.mapping, &child.stakes.mapping),
+
+            fee_pool: parent.fee_pool,
+            fee_multiplier: parent.fee_multiplier,
+            tips: parent.tips,
+            dosc_speed: parent.dosc_speed,
+
+            transactions: parent.transactions.values().cloned().collect(),
+            proposer_action: self.proposer_action().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::ProposerAction;
+
+    use crate::{
+        melvm::Covenant,
+        testing::functions::{create_state, faucet_tx, valid_txx},
+    };
+
+    #[test]
+    fn revert_restores_parent() {
+        let mut genesis = create_state(&HashMap::new(), 0);
+        genesis.apply_tx_batch(&[faucet_tx(0)]).unwrap();
+        let genesis = genesis.seal(Some(ProposerAction {
+            fee_multiplier_delta: 50,
+            reward_dest: Covenant::always_true().hash(),
+        }));
+
+        // two blocks, the first spending a coin and the second empty
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        let mut next = genesis.next_state();
+        next.apply_tx_batch(&[txx[0].clone(), faucet_tx(1), faucet_tx(2)])
+            .unwrap();
+        let block1 = next
+            .seal(Some(ProposerAction {
+                fee_multiplier_delta: -20,
+                reward_dest: Covenant::always_true().hash(),
+            }))
+            .to_block();
+        let (first, undo1) = genesis.apply_block_with_undo(&block1).unwrap();
+        assert!(undo1.spent.contains_key(&txx[0].inputs[0]));
+        assert_eq!(undo1.created.len(), 4);
+        let block2 = first.next_state().seal(None).to_block();
+        let (second, undo2) = first.apply_block_with_undo(&block2).unwrap();
+
+        // undo records only apply to their own block
+        assert!(second.revert(&undo1).is_none());
+        let reverted = second.revert(&undo2).unwrap();
+        assert_eq!(reverted.header(), first.header());
+        let reverted = reverted.revert(&undo1).unwrap();
+        assert_eq!(reverted.header(), genesis.header());
+        assert_eq!(
+            reverted.inner_ref().transactions,
+            genesis.inner_ref().transactions
+        );
+        assert_eq!(reverted.proposer_action(), genesis.proposer_action());
+        assert!(reverted.diff(&genesis).is_empty());
+        assert_eq!(
+            reverted.apply_block(&block1).unwrap().header(),
+            first.header()
+        );
+    }
+}
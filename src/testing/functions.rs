@@ -19,7 +19,7 @@ use novasmt::{Database, InMemoryCas};
 use tap::Tap;
 use themelio_structs::{
     Address, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID, StakeDoc, Transaction,
-    MICRO_CONVERTER,
+    TxKind, MICRO_CONVERTER,
 };
 use tmelcrypt::{Ed25519PK, Ed25519SK};
 
@@ -48,6 +48,24 @@ pub fn valid_txx(keypair: (Ed25519PK, Ed25519SK)) -> Vec<Transaction> {
     )
 }
 
+/// A faucet transaction that pays 1000 micromel to the always-true covenant, made unique by `i`.
+pub fn faucet_tx(i: u8) -> Transaction {
+    Transaction {
+        kind: TxKind::Faucet,
+        inputs: vec![],
+        outputs: vec![CoinData {
+            denom: Denom::Mel,
+            value: CoinValue(1000),
+            covhash: Covenant::always_true().hash(),
+            additional_data: vec![],
+        }],
+        fee: CoinValue(1_000_000),
+        covenants: vec![],
+        data: vec![i],
+        sigs: vec![],
+    }
+}
+
 /// Create a state using a mapping from sk to syms staked for an epoch
 pub fn create_state(
     stakers: &HashMap<Ed25519SK, CoinValue>,
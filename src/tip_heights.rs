@@ -17,3 +17,6 @@ pub const TIP_909_HEIGHT: BlockHeight = BlockHeight(950000);
 
 /// TIP 909a: tokenomics bugfix
 pub const TIP_909A_HEIGHT: BlockHeight = BlockHeight(1048000);
+
+/// TIP 911: slippage-protected swaps
+pub const TIP_911_HEIGHT: BlockHeight = BlockHeight(u64::MAX);
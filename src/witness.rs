@@ -0,0 +1,379 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use novasmt::{ContentAddrStore, Database, InMemoryCas};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use themelio_structs::{Block, BlockHeight, CoinID, CoinValue, Header};
+use thiserror::Error;
+use tmelcrypt::HashVal;
+
+use crate::{
+    smtnode::RawNode,
+    state::{advance_state, apply_block_to, check_block_size},
+    CoinMapping, ConsensusParams, SealedState, SmtMapping, State, StateError,
+};
+
+/// Everything besides the parent's header needed to check a block without the parent state.
+///
+/// This holds the raw SMT nodes that applying the block reads rather than a proof per input, since recomputing the roots after the block's writes needs whole hexary nodes, which proofs do not carry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockWitness {
+    /// The tips that the parent passes on, which its header does not commit to.
+    pub parent_tips: CoinValue,
+    /// The SMT nodes read while applying the block, by hash.
+    pub nodes: BTreeMap<HashVal, Vec<u8>>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// An error that happens while validating a block against a witness
+pub enum WitnessError {
+    #[error("witness node {0} does not match its hash")]
+    BadNode(HashVal),
+    #[error("witness is missing nodes needed to apply the block")]
+    Incomplete,
+    #[error("the proposer reward depends on tips that the parent's header does not commit to")]
+    UncheckedTips,
+    #[error(transparent)]
+    Invalid(#[from] StateError),
+}
+
+impl<C: ContentAddrStore> SealedState<C> {
+    /// Generates a witness for applying the given block to this state. Fails if the block is not valid.
+    pub fn block_witness(&self, block: &Block) -> Result<BlockWitness, StateError> {
+        let height = self.inner_ref().height;
+        check_block_size(&self.inner_ref().params, height + BlockHeight(1), block)?;
+        let (result, witness) = self.record_witness(|basis| {
+            // record what checking the tips reads
+            let _ = has_proposer_reward(&basis, height);
+            apply_block_to(basis, block)
+        });
+        result?;
+        Ok(witness)
+    }
+
+    /// Runs a function on the unfinalized state of the next block, built from this state's header, and records the SMT nodes it reads.
+    pub(crate) fn record_witness<R>(
+        &self,
+        f: impl FnOnce(State<RecordingCas<C>>) -> R,
+    ) -> (R, BlockWitness) {
+        let inner = self.inner_ref();
+        let db = Database::new(RecordingCas {
+            inner: inner.coins.inner().database(),
+            overlay: Default::default(),
+            recorded: Default::default(),
+        });
+        let result = f(basis_from_header(
+            &db,
+            &self.header(),
+            inner.params,
+            inner.tips,
+        ));
+        let nodes = std::mem::take(&mut *db.storage().recorded.lock());
+        (
+            result,
+            BlockWitness {
+                parent_tips: inner.tips,
+                nodes,
+            },
+        )
+    }
+}
+
+/// Validates a block on top of the parent with the given header using a witness, returning the block's recomputed header.
+///
+/// A block with a proposer action is only validated if the parent had one too, since the parent's tips cannot be checked otherwise.
+pub fn validate_block_stateless(
+    parent: &Header,
+    params: ConsensusParams,
+    block: &Block,
+    witness: &BlockWitness,
+) -> Result<Header, WitnessError> {
+    check_block_size(&params, parent.height + BlockHeight(1), block)?;
+    let sealed = replay_witness(parent, params, witness, |basis| {
+        let tips_known =
+            witness.parent_tips == CoinValue(0) && has_proposer_reward(&basis, parent.height);
+        if block.proposer_action.is_some() && !tips_known {
+            return Err(WitnessError::UncheckedTips);
+        }
+        Ok(apply_block_to(basis, block)?)
+    })??;
+    Ok(sealed.header())
+}
+
+/// Returns whether the block at the given height had a proposer action, which leaves a proposer reward coin behind.
+fn has_proposer_reward<C: ContentAddrStore>(basis: &State<C>, height: BlockHeight) -> bool {
+    basis
+        .coins
+        .get_coin(CoinID::proposer_reward(height))
+        .is_some()
+}
+
+/// Runs a function on the unfinalized state of the block after the given header, built from a witness instead of the parent state.
+///
+/// Nodes missing from the witness read as empty placeholders, so the replay runs to the end, and its result is then thrown away.
+pub(crate) fn replay_witness<R>(
+    parent: &Header,
+    params: ConsensusParams,
+    witness: &BlockWitness,
+    f: impl FnOnce(State<WitnessCas>) -> R,
+) -> Result<R, WitnessError> {
+    let db = Database::new(WitnessCas::load(parent, witness)?);
+    let basis = basis_from_header(&db, parent, params, witness.parent_tips);
+    if db.storage().missed.load(Ordering::SeqCst) {
+        return Err(WitnessError::Incomplete);
+    }
+    let result = f(basis);
+    if db.storage().missed.load(Ordering::SeqCst) {
+        return Err(WitnessError::Incomplete);
+    }
+    Ok(result)
+}
+
+/// Builds the unfinalized state of the block after the given header, with SMTs rooted at the roots it commits to.
+fn basis_from_header<C: ContentAddrStore>(
+    db: &Database<C>,
+    parent: &Header,
+    params: ConsensusParams,
+    tips: CoinValue,
+) -> State<C> {
+    let tree = |root: HashVal| db.get_tree(root.0).unwrap();
+    let state = State {
+        network: parent.network,
+        params,
+        height: parent.height,
+        history: SmtMapping::new(tree(parent.history_hash)),
+        coins: CoinMapping::new(tree(parent.coins_hash)),
+        transactions: Default::default(),
+        fee_pool: parent.fee_pool,
+        fee_multiplier: parent.fee_multiplier,
+        tips,
+        dosc_speed: parent.dosc_speed,
+        pools: SmtMapping::new(tree(parent.pools_hash)),
+        stakes: SmtMapping::new(tree(parent.stakes_hash)),
+    };
+    advance_state(
+        state,
+        *parent,
+        None,
+        u64::MAX,
+        |_| ControlFlow::Continue(()),
+    )
+    .expect("migration failed without a checkpoint")
+}
+
+/// A content-addressed store holding the nodes of a witness, which notes whether a node was ever looked up that the witness lacks.
+#[derive(Default)]
+pub(crate) struct WitnessCas {
+    nodes: InMemoryCas,
+    /// The height of every node that hangs off the parent's roots, whether the witness has it or not.
+    heights: BTreeMap<[u8; 32], u8>,
+    missed: AtomicBool,
+}
+
+impl WitnessCas {
+    /// Loads the nodes of a witness, checking them against their hashes and against the heights at which the parent's roots place them.
+    fn load(parent: &Header, witness: &BlockWitness) -> Result<Self, WitnessError> {
+        let mut nodes = BTreeMap::new();
+        for (hash, node) in witness.nodes.iter() {
+            let node = RawNode::decode(node)
+                .filter(|node| node.hash() == hash.0)
+                .ok_or(WitnessError::BadNode(*hash))?;
+            nodes.insert(hash.0, node);
+        }
+
+        let mut cas = Self::default();
+        let mut stack: Vec<([u8; 32], u8)> = [
+            parent.history_hash,
+            parent.coins_hash,
+            parent.pools_hash,
+            parent.stakes_hash,
+        ]
+        .iter()
+        .map(|root| (root.0, 64))
+        .collect();
+        while let Some((hash, height)) = stack.pop() {
+            if hash == [0; 32] {
+                continue;
+            }
+            match cas.heights.insert(hash, height) {
+                Some(seen) if seen == height => continue,
+                Some(_) => return Err(WitnessError::BadNode(HashVal(hash))),
+                None => {}
+            }
+            // a hexary node's hash covers neither its height nor its count, so both are set here
+            let node = match nodes.remove(&hash) {
+                None => continue,
+                Some(RawNode::Single { height: h, .. }) if h != height => {
+                    return Err(WitnessError::BadNode(HashVal(hash)))
+                }
+                Some(RawNode::Hexary { .. }) if height == 0 => {
+                    return Err(WitnessError::BadNode(HashVal(hash)))
+                }
+                Some(RawNode::Hexary { children, .. }) => {
+                    stack.extend(children.iter().map(|child| (*child, height - 1)));
+                    RawNode::Hexary {
+                        height,
+                        count: 1 << 62,
+                        children,
+                    }
+                }
+                Some(node) => node,
+            };
+            cas.nodes.insert(&hash, &node.encode());
+        }
+        Ok(cas)
+    }
+}
+
+impl ContentAddrStore for WitnessCas {
+    fn get<'a>(&'a self, key: &[u8]) -> Option<Cow<'a, [u8]>> {
+        if let Some(node) = self.nodes.get(key) {
+            return Some(node);
+        }
+        // a missing node reads as an empty one, so that the SMT does not panic on it
+        let height = *self.heights.get(key)?;
+        self.missed.store(true, Ordering::SeqCst);
+        let placeholder = if height == 0 {
+            RawNode::Single {
+                height,
+                key: [0; 32],
+                value: &[],
+            }
+        } else {
+            RawNode::Hexary {
+                height,
+                count: 1 << 62,
+                children: Box::new([[0; 32]; 16]),
+            }
+        };
+        Some(Cow::Owned(placeholder.encode()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) {
+        self.nodes.insert(key, value)
+    }
+}
+
+/// A content-addressed store that reads from a database, records every node it reads, and keeps what is written to it in memory.
+pub(crate) struct RecordingCas<C: ContentAddrStore> {
+    inner: Database<C>,
+    overlay: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    recorded: Mutex<BTreeMap<HashVal, Vec<u8>>>,
+}
+
+impl<C: ContentAddrStore> ContentAddrStore for RecordingCas<C> {
+    fn get<'a>(&'a self, key: &[u8]) -> Option<Cow<'a, [u8]>> {
+        if let Some(node) = self.overlay.lock().get(key) {
+            return Some(Cow::Owned(node.clone()));
+        }
+        let node = self.inner.storage().get(key)?.to_vec();
+        let hash = key
+            .try_into()
+            .expect("SMT nodes are stored under their hashes");
+        self.recorded.lock().insert(HashVal(hash), node.clone());
+        Some(Cow::Owned(node))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) {
+        self.overlay.lock().insert(key.to_vec(), value.to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use themelio_structs::{CoinValue, ProposerAction};
+
+    use crate::{
+        melvm::Covenant,
+        testing::functions::{create_state, faucet_tx, valid_txx},
+        validate_block_stateless, StateError, WitnessError,
+    };
+
+    #[test]
+    fn stateless_validation() {
+        let action = ProposerAction {
+            fee_multiplier_delta: 10,
+            reward_dest: Covenant::always_true().hash(),
+        };
+        let parent = create_state(&HashMap::new(), 0).seal(Some(action));
+        let txx = valid_txx(tmelcrypt::ed25519_keygen());
+        let mut next = parent.next_state();
+        next.apply_tx_batch(&[txx[0].clone(), faucet_tx(0)])
+            .unwrap();
+        let block = next.seal(Some(action)).to_block();
+
+        let params = parent.inner_ref().params;
+        let witness = parent.block_witness(&block).unwrap();
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &block, &witness),
+            Ok(block.header)
+        );
+
+        // a tampered block is rejected
+        let mut bad_block = block.clone();
+        bad_block.header.fee_pool += CoinValue(1);
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &bad_block, &witness),
+            Err(WitnessError::Invalid(StateError::WrongHeader))
+        );
+
+        // so are tampered and incomplete witnesses
+        let mut bad_witness = witness.clone();
+        let (hash, node) = bad_witness.nodes.iter_mut().next().unwrap();
+        let hash = *hash;
+        *node.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &block, &bad_witness),
+            Err(WitnessError::BadNode(hash))
+        );
+        // a hexary node's height is not covered by its hash, so it is taken from where the node hangs
+        let mut misplaced = witness.clone();
+        let (hash, node) = misplaced
+            .nodes
+            .iter_mut()
+            .find(|(_, node)| node[0] > 1)
+            .unwrap();
+        let hash = *hash;
+        node[0] -= 1;
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &block, &misplaced),
+            Ok(block.header)
+        );
+        let mut incomplete = witness.clone();
+        incomplete.nodes.remove(&hash);
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &block, &incomplete),
+            Err(WitnessError::Incomplete)
+        );
+
+        // tips that would change the proposer reward are rejected
+        let mut tampered = witness;
+        tampered.parent_tips += CoinValue(1);
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &block, &tampered),
+            Err(WitnessError::UncheckedTips)
+        );
+
+        // and so are the tips of a parent without a proposer action, which cannot be checked
+        let parent = create_state(&HashMap::new(), 0).seal(None);
+        let child = parent.next_state().seal(Some(action)).to_block();
+        let witness = parent.block_witness(&child).unwrap();
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &child, &witness),
+            Err(WitnessError::UncheckedTips)
+        );
+        let child = parent.next_state().seal(None).to_block();
+        let witness = parent.block_witness(&child).unwrap();
+        assert_eq!(
+            validate_block_stateless(&parent.header(), params, &child, &witness),
+            Ok(child.header)
+        );
+    }
+}